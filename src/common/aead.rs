@@ -0,0 +1,48 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes128Gcm, Aes256Gcm,
+};
+use chacha20poly1305::ChaCha20Poly1305;
+use worker::*;
+
+pub const AEAD_TAG_SIZE: usize = 16;
+pub const AEAD_NONCE_SIZE: usize = 12;
+
+// aes key schedules are much larger than chacha's, so keep them boxed
+pub enum AeadCipher {
+    Aes128Gcm(Box<Aes128Gcm>),
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+impl AeadCipher {
+    pub fn aes_128_gcm(key: &[u8]) -> Self {
+        Self::Aes128Gcm(Box::new(Aes128Gcm::new(key.into())))
+    }
+
+    pub fn aes_256_gcm(key: &[u8]) -> Self {
+        Self::Aes256Gcm(Box::new(Aes256Gcm::new(key.into())))
+    }
+
+    pub fn chacha20_poly1305(key: &[u8]) -> Self {
+        Self::ChaCha20Poly1305(Box::new(ChaCha20Poly1305::new(key.into())))
+    }
+
+    pub fn encrypt(&self, nonce: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Aes128Gcm(c) => c.encrypt(nonce.into(), data),
+            Self::Aes256Gcm(c) => c.encrypt(nonce.into(), data),
+            Self::ChaCha20Poly1305(c) => c.encrypt(nonce.into(), data),
+        }
+        .map_err(|e| Error::RustError(e.to_string()))
+    }
+
+    pub fn decrypt(&self, nonce: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Aes128Gcm(c) => c.decrypt(nonce.into(), data),
+            Self::Aes256Gcm(c) => c.decrypt(nonce.into(), data),
+            Self::ChaCha20Poly1305(c) => c.decrypt(nonce.into(), data),
+        }
+        .map_err(|e| Error::RustError(e.to_string()))
+    }
+}
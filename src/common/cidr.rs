@@ -0,0 +1,108 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// https://www.cloudflare.com/ips/
+pub const CLOUDFLARE_RANGES: &[&str] = &[
+    "173.245.48.0/20",
+    "103.21.244.0/22",
+    "103.22.200.0/22",
+    "103.31.4.0/22",
+    "141.101.64.0/18",
+    "108.162.192.0/18",
+    "190.93.240.0/20",
+    "188.114.96.0/20",
+    "197.234.240.0/22",
+    "198.41.128.0/17",
+    "162.158.0.0/15",
+    "104.16.0.0/13",
+    "104.24.0.0/14",
+    "172.64.0.0/13",
+    "131.0.72.0/22",
+    "2400:cb00::/32",
+    "2606:4700::/32",
+    "2803:f800::/32",
+    "2405:b500::/32",
+    "2405:8100::/32",
+    "2a06:98c0::/29",
+    "2c0f:f248::/32",
+];
+
+#[derive(Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').ok_or(format!("invalid cidr: {s}"))?;
+        let addr: IpAddr = addr.parse().map_err(|_| format!("invalid cidr: {s}"))?;
+        let prefix: u8 = prefix.parse().map_err(|_| format!("invalid cidr: {s}"))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max_prefix {
+            return Err(format!("invalid cidr: {s}"));
+        }
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+// one cidr per line, the same format as https://www.cloudflare.com/ips-v4.
+// also returns how many lines were rejected, blank lines aside.
+pub fn parse_cidrs(list: &str) -> (Vec<Cidr>, usize) {
+    let mut rejected = 0;
+    let cidrs = list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| line.parse().map_err(|_| rejected += 1).ok())
+        .collect();
+    (cidrs, rejected)
+}
+
+pub fn cloudflare_cidrs() -> Vec<Cidr> {
+    parse_cidrs(&CLOUDFLARE_RANGES.join("\n")).0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cloudflare_cidrs() {
+        let cidrs = cloudflare_cidrs();
+        assert_eq!(cidrs.len(), CLOUDFLARE_RANGES.len());
+
+        let is_cloudflare = |ip: &str| cidrs.iter().any(|cidr| cidr.contains(&ip.parse().unwrap()));
+        assert!(is_cloudflare("104.16.132.229"));
+        assert!(is_cloudflare("172.67.1.1"));
+        assert!(is_cloudflare("2606:4700:4700::1111"));
+        assert!(!is_cloudflare("8.8.8.8"));
+        assert!(!is_cloudflare("2001:4860:4860::8888"));
+    }
+
+    #[test]
+    fn test_parse_cidrs() {
+        let (cidrs, rejected) = parse_cidrs("104.16.0.0/13\n\n  2606:4700::/32\n1.2.3.4\n10.0.0.0/33\n");
+        assert_eq!(cidrs.len(), 2);
+        assert_eq!(rejected, 2);
+    }
+}
@@ -1,10 +1,16 @@
+pub mod aead;
+pub mod cidr;
 pub mod hash;
+pub mod replay;
 
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
 use tokio::io::AsyncReadExt; // dari worker crate
 use tokio::io::AsyncRead;     // untuk trait
 use worker::*;
 
+pub const KDFSALT_CONST_AUTH_ID_ENCRYPTION_KEY: &[u8] = b"AES Auth ID Encryption";
+pub const KDFSALT_CONST_AUTH_LEN: &[u8] = b"auth_len";
 pub const KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY: &[u8] =
     b"VMess Header AEAD Key_Length";
 pub const KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV: &[u8] =
@@ -42,35 +48,89 @@ macro_rules! sha256 {
     }
 }
 
-pub async fn parse_addr<R: AsyncRead + std::marker::Unpin>(buf: &mut R) -> Result<String> {
-    // combined addr type between Vmess, VLESS, and Trojan.
-    // VLESS wouldn't connect to ipv6 address due to mismatch addr type
+pub fn unix_time() -> u64 {
+    Date::now().as_millis() / 1000
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Socket::connect takes ipv6 literals in brackets
+pub fn socket_host(ip: &IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{ip}]"),
+    }
+}
+
+// RFC 6052 ipv4-embedded ipv6 address with a /96 prefix
+pub fn nat64(prefix: &Ipv6Addr, ip: &Ipv4Addr) -> Ipv6Addr {
+    Ipv6Addr::from((u128::from(*prefix) & !0xffff_ffff) | u32::from(*ip) as u128)
+}
+
+// early data sent in the Sec-WebSocket-Protocol header. clients use base64url,
+// but some pad it or use the standard alphabet.
+pub fn decode_early_data(header: &str) -> Option<Vec<u8>> {
+    let encoded = header.trim().trim_end_matches('=').replace('+', "-").replace('/', "_");
+    if encoded.is_empty() {
+        return None;
+    }
+    URL_SAFE_NO_PAD.decode(encoded).ok()
+}
+
+// address type values used by each protocol
+#[derive(Clone, Copy)]
+pub struct AddrCodec {
+    pub ipv4: u8,
+    pub domain: u8,
+    pub ipv6: u8,
+}
+
+// Trojan and Shadowsocks, same as socks5
+pub const SOCKS_ADDR: AddrCodec = AddrCodec {
+    ipv4: 1,
+    domain: 3,
+    ipv6: 4,
+};
+
+// VLESS and Vmess
+pub const VLESS_ADDR: AddrCodec = AddrCodec {
+    ipv4: 1,
+    domain: 2,
+    ipv6: 3,
+};
+
+// inverse of parse_addr
+pub fn encode_addr(addr: &str, codec: AddrCodec) -> Vec<u8> {
+    match addr.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => [&[codec.ipv4][..], &ip.octets()].concat(),
+        Ok(IpAddr::V6(ip)) => [&[codec.ipv6][..], &ip.octets()].concat(),
+        Err(_) => [&[codec.domain, addr.len() as u8][..], addr.as_bytes()].concat(),
+    }
+}
+
+pub async fn parse_addr<R: AsyncRead + std::marker::Unpin>(buf: &mut R, codec: AddrCodec) -> Result<String> {
     let addr = match buf.read_u8().await? {
-        1 => {
+        atyp if atyp == codec.ipv4 => {
             let mut addr = [0u8; 4];
             buf.read_exact(&mut addr).await?;
             Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]).to_string()
         }
-        2 | 3 => {
+        atyp if atyp == codec.domain => {
             let len = buf.read_u8().await?;
             let mut domain = vec![0u8; len as _];
             buf.read_exact(&mut domain).await?;
             String::from_utf8_lossy(&domain).to_string()
         }
-        4 => {
+        atyp if atyp == codec.ipv6 => {
             let mut addr = [0u8; 16];
             buf.read_exact(&mut addr).await?;
-            Ipv6Addr::new(
-                ((addr[0] as u16) << 16) | (addr[1] as u16),
-                ((addr[2] as u16) << 16) | (addr[3] as u16),
-                ((addr[4] as u16) << 16) | (addr[5] as u16),
-                ((addr[6] as u16) << 16) | (addr[7] as u16),
-                ((addr[8] as u16) << 16) | (addr[9] as u16),
-                ((addr[10] as u16) << 16) | (addr[11] as u16),
-                ((addr[12] as u16) << 16) | (addr[13] as u16),
-                ((addr[14] as u16) << 16) | (addr[15] as u16),
-            )
-            .to_string()
+            Ipv6Addr::from(addr).to_string()
         }
         _ => {
             return Err(Error::RustError("invalid address".to_string()));
@@ -79,3 +139,77 @@ pub async fn parse_addr<R: AsyncRead + std::marker::Unpin>(buf: &mut R) -> Resul
 
     Ok(addr)
 }
+
+#[cfg(test)]
+pub fn from_hex(hex: &str) -> Vec<u8> {
+    let hex: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+pub fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &[u8], codec: AddrCodec) -> Result<String> {
+        let mut buf = std::io::Cursor::new(data.to_vec());
+        block_on(parse_addr(&mut buf, codec))
+    }
+
+    #[test]
+    fn test_parse_addr() {
+        let ipv6 = [
+            0x20, 0x01, 0x0d, 0xb8, 0x85, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x8a, 0x2e, 0x03, 0x70,
+            0x73, 0x34,
+        ];
+
+        let mut vless = vec![3];
+        vless.extend_from_slice(&ipv6);
+        assert_eq!(parse(&vless, VLESS_ADDR).unwrap(), "2001:db8:85a3::8a2e:370:7334");
+
+        let mut socks = vec![4];
+        socks.extend_from_slice(&ipv6);
+        assert_eq!(parse(&socks, SOCKS_ADDR).unwrap(), "2001:db8:85a3::8a2e:370:7334");
+
+        assert_eq!(parse(&[1, 1, 1, 1, 1], SOCKS_ADDR).unwrap(), "1.1.1.1");
+        assert_eq!(parse(b"\x02\x07example", VLESS_ADDR).unwrap(), "example");
+        assert!(parse(b"\x02\x07example", SOCKS_ADDR).is_err());
+    }
+
+    #[test]
+    fn test_encode_addr() {
+        for addr in ["1.1.1.1", "2001:db8:85a3::8a2e:370:7334", "example.com"] {
+            assert_eq!(parse(&encode_addr(addr, SOCKS_ADDR), SOCKS_ADDR).unwrap(), addr);
+            assert_eq!(parse(&encode_addr(addr, VLESS_ADDR), VLESS_ADDR).unwrap(), addr);
+        }
+    }
+
+    #[test]
+    fn test_nat64() {
+        let prefix = "64:ff9b::".parse().unwrap();
+        assert_eq!(nat64(&prefix, &Ipv4Addr::new(192, 0, 2, 33)).to_string(), "64:ff9b::c000:221");
+    }
+
+    #[test]
+    fn test_socket_host() {
+        assert_eq!(socket_host(&"1.1.1.1".parse().unwrap()), "1.1.1.1");
+        assert_eq!(socket_host(&"2606:4700:4700::1111".parse().unwrap()), "[2606:4700:4700::1111]");
+    }
+
+    #[test]
+    fn test_decode_early_data() {
+        assert_eq!(decode_early_data("AAEC_w").unwrap(), [0, 1, 2, 255]);
+        assert_eq!(decode_early_data("AAEC/w==").unwrap(), [0, 1, 2, 255]);
+        assert!(decode_early_data("").is_none());
+        assert!(decode_early_data("not early data!").is_none());
+    }
+}
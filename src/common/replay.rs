@@ -0,0 +1,27 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// remembers handshake nonces (auth ids, salts) until they expire.
+//
+// the cache lives in the isolate's memory, so it only covers connections served by
+// the same isolate. cloudflare runs many isolates across colos and may evict one at
+// any time, so a replay that lands elsewhere or after an eviction is not caught.
+// a global check would need a durable object on every handshake.
+pub struct ReplayCache {
+    seen: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl ReplayCache {
+    pub fn new() -> Self {
+        Self {
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // returns false if the nonce was already seen and hasn't expired yet
+    pub fn insert(&self, nonce: &[u8], expires_at: u64, now: u64) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, expiry| *expiry >= now);
+        seen.insert(nonce.to_vec(), expires_at).is_none()
+    }
+}
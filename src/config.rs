@@ -1,11 +1,257 @@
+use crate::common::cidr::Cidr;
+use crate::common::{nat64, socket_host, unix_time};
+use crate::proxy::shadowsocks::Method;
+
+use std::net::{IpAddr, Ipv6Addr};
+
+use serde::Deserialize;
 use uuid::Uuid;
 
 pub struct Config {
-    pub uuid: Uuid,
+    pub users: Vec<User>,
     pub host: String,
+    pub client_ip: String,
     pub proxy_addr: String,
     pub proxy_port: u16,
+    // destinations workers can't connect to, see dial_targets
+    pub cloudflare_cidrs: Vec<Cidr>,
+    pub fallback: Fallback,
+    pub nat64_prefix: Option<Ipv6Addr>,
+    // upper bound for client data held in memory per connection
+    pub max_buffer_size: usize,
+    pub ss_method: Method,
+    pub dns_upstream: DnsUpstream,
+    // set when the path or query pins a protocol, otherwise it's detected
+    pub protocol: Option<Protocol>,
+    pub disabled_protocols: Vec<Protocol>,
 
     pub main_page_url: String,
     pub sub_page_url: String,
 }
+
+impl Config {
+    pub fn is_cloudflare(&self, ip: &IpAddr) -> bool {
+        self.cloudflare_cidrs.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    // where to connect for a destination, in order: the direct dial, then whatever
+    // FALLBACK adds. cloudflare destinations skip the direct dial and always end with
+    // the proxy ip, even with direct-only, as nothing else is sure to reach them.
+    // plain connections and mux sessions both go through here.
+    pub fn dial_targets(&self, ip: Option<IpAddr>, port: u16) -> Vec<(String, u16)> {
+        let cloudflare = ip.is_some_and(|ip| self.is_cloudflare(&ip));
+        let mut targets = Vec::new();
+        if let Some(ip) = ip.filter(|_| !cloudflare) {
+            targets.push((socket_host(&ip), port));
+        }
+        if self.fallback.uses_nat64() {
+            if let (Some(IpAddr::V4(ip)), Some(prefix)) = (ip, &self.nat64_prefix) {
+                targets.push((socket_host(&IpAddr::V6(nat64(prefix, &ip))), port));
+            }
+        }
+        if self.fallback.uses_proxy_ip() || cloudflare {
+            targets.push((self.proxy_addr.clone(), self.proxy_port));
+        }
+        targets
+    }
+
+    pub fn is_enabled(&self, protocol: Protocol) -> bool {
+        !self.disabled_protocols.contains(&protocol)
+    }
+
+    pub fn active_users(&self) -> Vec<User> {
+        let now = unix_time();
+        self.users
+            .iter()
+            .filter(|user| user.is_active(now))
+            .cloned()
+            .collect()
+    }
+}
+
+// where to retry when the direct dial fails, cloudflare destinations aside
+#[derive(Clone, Copy, PartialEq)]
+pub enum Fallback {
+    DirectOnly,
+    ProxyIp,
+    Nat64,
+    Nat64ThenProxyIp,
+}
+
+impl Fallback {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "direct-only" => Ok(Self::DirectOnly),
+            "proxyip" => Ok(Self::ProxyIp),
+            "nat64" => Ok(Self::Nat64),
+            "nat64-then-proxyip" => Ok(Self::Nat64ThenProxyIp),
+            _ => Err(format!("unsupported fallback: {name}")),
+        }
+    }
+
+    pub fn uses_nat64(&self) -> bool {
+        matches!(self, Self::Nat64 | Self::Nat64ThenProxyIp)
+    }
+
+    pub fn uses_proxy_ip(&self) -> bool {
+        matches!(self, Self::ProxyIp | Self::Nat64ThenProxyIp)
+    }
+}
+
+// where dns queries that aren't addressed to a specific server go
+#[derive(Clone, Debug, PartialEq)]
+pub enum DnsUpstream {
+    // https://1.1.1.1/dns-query
+    Doh(String),
+    // tls://1.1.1.1[:853]
+    Dot(String, u16),
+    // tcp://8.8.8.8[:53]
+    Tcp(String, u16),
+}
+
+impl DnsUpstream {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        if name.starts_with("https://") {
+            Ok(Self::Doh(name.to_string()))
+        } else if let Some(addr) = name.strip_prefix("tls://") {
+            let (host, port) = parse_host_port(addr, 853)?;
+            Ok(Self::Dot(host, port))
+        } else if let Some(addr) = name.strip_prefix("tcp://") {
+            let (host, port) = parse_host_port(addr, 53)?;
+            Ok(Self::Tcp(host, port))
+        } else {
+            Err(format!("unsupported dns upstream: {name}"))
+        }
+    }
+}
+
+impl Default for DnsUpstream {
+    fn default() -> Self {
+        Self::Doh("https://1.1.1.1/dns-query".to_string())
+    }
+}
+
+// bare ipv6 addresses are bracketed for Socket::connect
+fn parse_host_port(addr: &str, default_port: u16) -> Result<(String, u16), String> {
+    if let Ok(ip) = addr.parse::<IpAddr>() {
+        return Ok((socket_host(&ip), default_port));
+    }
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !addr.ends_with(']') => {
+            let port = port.parse().map_err(|_| format!("invalid dns port: {port}"))?;
+            Ok((host.to_string(), port))
+        }
+        _ => Ok((addr.to_string(), default_port)),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Vless,
+    Trojan,
+    Vmess,
+    Shadowsocks,
+}
+
+impl Protocol {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "vless" => Ok(Self::Vless),
+            "trojan" => Ok(Self::Trojan),
+            "vmess" => Ok(Self::Vmess),
+            "ss" => Ok(Self::Shadowsocks),
+            _ => Err(format!("unsupported protocol: {name}")),
+        }
+    }
+
+    // comma separated, e.g. "vmess,ss"
+    pub fn parse_list(list: &str) -> Result<Vec<Self>, String> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(Self::from_name)
+            .collect()
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct User {
+    pub id: Uuid,
+    #[serde(default)]
+    pub label: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    // unix timestamp in seconds
+    #[serde(default)]
+    pub expiry: Option<u64>,
+    // trojan and shadowsocks password, defaults to the user id
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl User {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.enabled && self.expiry.is_none_or(|expiry| now < expiry)
+    }
+
+    pub fn password(&self) -> String {
+        self.password.clone().unwrap_or_else(|| self.id.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dns_upstream() {
+        assert_eq!(DnsUpstream::from_name("https://dns.google/dns-query").unwrap(), DnsUpstream::Doh("https://dns.google/dns-query".to_string()));
+        assert_eq!(DnsUpstream::from_name("tls://1.1.1.1").unwrap(), DnsUpstream::Dot("1.1.1.1".to_string(), 853));
+        assert_eq!(DnsUpstream::from_name("tcp://8.8.8.8:5353").unwrap(), DnsUpstream::Tcp("8.8.8.8".to_string(), 5353));
+        assert_eq!(DnsUpstream::from_name("tcp://2001:4860:4860::8888").unwrap(), DnsUpstream::Tcp("[2001:4860:4860::8888]".to_string(), 53));
+        assert_eq!(DnsUpstream::from_name("tcp://[2001:4860:4860::8888]:53").unwrap(), DnsUpstream::Tcp("[2001:4860:4860::8888]".to_string(), 53));
+        assert_eq!(DnsUpstream::from_name("tls://dns.google").unwrap(), DnsUpstream::Dot("dns.google".to_string(), 853));
+        assert!(DnsUpstream::from_name("udp://8.8.8.8").is_err());
+        assert!(DnsUpstream::from_name("tcp://8.8.8.8:dns").is_err());
+    }
+
+    #[test]
+    fn test_dial_targets() {
+        let mut config = Config {
+            users: Vec::new(),
+            host: "example.com".to_string(),
+            client_ip: String::new(),
+            proxy_addr: "proxy.example.com".to_string(),
+            proxy_port: 443,
+            cloudflare_cidrs: vec!["104.16.0.0/13".parse().unwrap()],
+            fallback: Fallback::DirectOnly,
+            nat64_prefix: Some("64:ff9b::".parse().unwrap()),
+            max_buffer_size: 0,
+            ss_method: Method::from_name("none").unwrap(),
+            dns_upstream: DnsUpstream::Tcp("8.8.8.8".to_string(), 53),
+            protocol: None,
+            disabled_protocols: Vec::new(),
+            main_page_url: String::new(),
+            sub_page_url: String::new(),
+        };
+        let target = |host: &str, port| (host.to_string(), port);
+        let direct = Some("1.2.3.4".parse().unwrap());
+        let cloudflare = Some("104.16.1.1".parse().unwrap());
+
+        assert_eq!(config.dial_targets(direct, 80), [target("1.2.3.4", 80)]);
+        assert_eq!(config.dial_targets(cloudflare, 80), [target("proxy.example.com", 443)]);
+        assert!(config.dial_targets(None, 80).is_empty());
+
+        config.fallback = Fallback::Nat64;
+        assert_eq!(config.dial_targets(direct, 80), [target("1.2.3.4", 80), target("[64:ff9b::102:304]", 80)]);
+        assert_eq!(config.dial_targets(cloudflare, 80), [target("[64:ff9b::6810:101]", 80), target("proxy.example.com", 443)]);
+
+        config.fallback = Fallback::ProxyIp;
+        assert_eq!(config.dial_targets(None, 80), [target("proxy.example.com", 443)]);
+        assert_eq!(config.dial_targets(cloudflare, 80), [target("proxy.example.com", 443)]);
+    }
+}
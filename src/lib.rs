@@ -2,11 +2,16 @@ mod common;
 mod config;
 mod proxy;
 
-use crate::config::Config;
+use crate::common::cidr::{cloudflare_cidrs, parse_cidrs};
+use crate::common::decode_early_data;
+use crate::config::{Config, DnsUpstream, Fallback, Protocol, User};
 use crate::proxy::*;
+use crate::proxy::shadowsocks::Method;
 
 use std::collections::HashMap;
+use std::net::Ipv6Addr;
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use bytes::BufMut;
 use serde::Serialize;
 use serde_json::json;
 use uuid::Uuid;
@@ -14,27 +19,92 @@ use worker::*;
 use once_cell::sync::Lazy;
 use regex::Regex;
 
+const DEFAULT_MAX_BUFFER_SIZE: usize = 1024 * 1024;
+
 static PROXYIP_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new(r"^.+-\d+$").unwrap());
 
 #[event(fetch)]
 async fn main(req: Request, env: Env, _: Context) -> Result<Response> {
-    let uuid = env
-        .var("UUID")
-        .map(|x| Uuid::parse_str(&x.to_string()).unwrap_or_default())?;
+    let users = load_users(&env).await?;
     let host = req.url()?.host().map(|x| x.to_string()).unwrap_or_default();
+    let client_ip = req.headers().get("CF-Connecting-IP")?.unwrap_or_default();
     let main_page_url = env.var("MAIN_PAGE_URL").map(|x|x.to_string()).unwrap();
     let sub_page_url = env.var("SUB_PAGE_URL").map(|x|x.to_string()).unwrap();
-    let config = Config { uuid, host: host.clone(), proxy_addr: host, proxy_port: 443, main_page_url, sub_page_url};
+    // plain shadowsocks has no credentials, so it has to be asked for with SS_METHOD=none
+    let ss_method = env.var("SS_METHOD").map(|x| x.to_string()).unwrap_or("chacha20-ietf-poly1305".to_string());
+    let ss_method = Method::from_name(&ss_method)?;
+    let fallback = env.var("FALLBACK").map(|x| x.to_string()).unwrap_or("proxyip".to_string());
+    let fallback = Fallback::from_name(&fallback).map_err(Error::RustError)?;
+    // only /96 prefixes are supported, e.g. 64:ff9b::/96
+    let nat64_prefix = match env.var("NAT64_PREFIX") {
+        Ok(x) => Some(
+            x.to_string()
+                .trim_end_matches("/96")
+                .parse::<Ipv6Addr>()
+                .map_err(|e| Error::RustError(format!("invalid NAT64_PREFIX: {e}")))?,
+        ),
+        Err(_) => None,
+    };
+    let max_buffer_size = env
+        .var("MAX_BUFFER_SIZE")
+        .ok()
+        .and_then(|x| x.to_string().parse().ok())
+        .unwrap_or(DEFAULT_MAX_BUFFER_SIZE);
+    // https://url for doh, tls://host[:853] for dot, tcp://host[:53] for plain dns over tcp
+    let dns_upstream = match env.var("DNS_UPSTREAM") {
+        Ok(x) => DnsUpstream::from_name(&x.to_string()).map_err(Error::RustError)?,
+        Err(_) => DnsUpstream::default(),
+    };
+    let disabled_protocols = match env.var("DISABLED_PROTOCOLS") {
+        Ok(x) => Protocol::parse_list(&x.to_string()).map_err(Error::RustError)?,
+        Err(_) => Vec::new(),
+    };
+    let config = Config { users, host: host.clone(), client_ip, proxy_addr: host, proxy_port: 443, cloudflare_cidrs: cloudflare_cidrs(), fallback, nat64_prefix, max_buffer_size, ss_method, dns_upstream, protocol: None, disabled_protocols, main_page_url, sub_page_url};
+    // without a valid psk every 2022 handshake would fail, and the uuid is no stand-in for one
+    if config.ss_method.is_2022() && config.is_enabled(Protocol::Shadowsocks) {
+        for user in config.active_users() {
+            config
+                .ss_method
+                .password_to_key(&user.password())
+                .map_err(|e| Error::RustError(format!("user {} has no valid shadowsocks psk: {e}", user.label)))?;
+        }
+    }
 
     Router::with_data(config)
         .on_async("/", fe)
         .on_async("/sub", sub)
         .on("/link", link)
         .on_async("/:proxyip", tunnel)
+        .on_async("/:protocol/:proxyip", tunnel)
         .run(req, env)
         .await
 }
 
+async fn load_users(env: &Env) -> Result<Vec<User>> {
+    // USERS var takes precedence over the users key in kv
+    let users_str = match env.var("USERS") {
+        Ok(x) => Some(x.to_string()),
+        Err(_) => env.kv("catme")?.get("users").text().await?,
+    };
+
+    if let Some(users_str) = users_str {
+        return Ok(serde_json::from_str(&users_str)?);
+    }
+
+    // fallback to the single UUID user
+    let id = env
+        .var("UUID")
+        .map(|x| Uuid::parse_str(&x.to_string()).unwrap_or_default())?;
+    let password = env.var("TROJAN_PASSWORD").map(|x| x.to_string()).ok();
+    Ok(vec![User {
+        id,
+        label: "default".to_string(),
+        enabled: true,
+        expiry: None,
+        password,
+    }])
+}
+
 async fn get_response_from_url(url: String) -> Result<Response> {
     let req = Fetch::Url(Url::parse(url.as_str())?);
     let mut res = req.send().await?;
@@ -52,11 +122,41 @@ async fn sub(_: Request, cx: RouteContext<Config>) -> Result<Response> {
 
 async fn tunnel(req: Request, mut cx: RouteContext<Config>) -> Result<Response> {
     let mut proxyip = cx.param("proxyip").unwrap().to_string();
+    // the path pins the protocol first, then ?proto=
+    let protocol = match cx.param("protocol") {
+        Some(name) => Some(name.to_string()),
+        None => req
+            .url()?
+            .query_pairs()
+            .find(|(key, _)| key == "proto")
+            .map(|(_, value)| value.to_string()),
+    };
+    if let Some(name) = protocol {
+        let protocol = match Protocol::from_name(&name) {
+            Ok(protocol) => protocol,
+            Err(e) => return Response::error(e, 404),
+        };
+        if !cx.data.is_enabled(protocol) {
+            return Response::error("protocol disabled", 403);
+        }
+        cx.data.protocol = Some(protocol);
+    }
+    let kv = cx.kv("catme")?;
+    if let Some(cidrs) = kv.get("cf_cidrs").text().await? {
+        let (cidrs, rejected) = parse_cidrs(&cidrs);
+        if rejected > 0 {
+            console_error!("cf_cidrs: rejected {} invalid lines", rejected);
+        }
+        // an empty or broken list keeps the built-in ranges
+        if !cidrs.is_empty() {
+            cx.data.cloudflare_cidrs = cidrs;
+        }
+    }
+
     if proxyip.len() == 2 {
-        let kv = cx.kv("catme")?;
         let mut proxy_kv_str = kv.get("proxy_kv").text().await?.unwrap_or("".to_string());
 
-        if proxy_kv_str.len() == 0 {
+        if proxy_kv_str.is_empty() {
             console_log!("getting proxy kv from github...");
             let req = Fetch::Url(Url::parse("https://raw.githubusercontent.com/FoolVPN-ID/Nautica/refs/heads/main/kvProxyList.json")?);
             let mut res = req.send().await?;
@@ -82,18 +182,31 @@ async fn tunnel(req: Request, mut cx: RouteContext<Config>) -> Result<Response>
     }
     
     let upgrade = req.headers().get("Upgrade")?.unwrap_or("".to_string());
-    if upgrade == "websocket".to_string() {
+    if upgrade == "websocket" {
+        // 0-rtt: the first payload rides in the subprotocol header, which has to be echoed
+        let ws_protocol = req.headers().get("Sec-WebSocket-Protocol")?;
+        let early_data = ws_protocol.as_deref().and_then(decode_early_data);
         let WebSocketPair { server, client } = WebSocketPair::new()?;
         server.accept()?;
     
         wasm_bindgen_futures::spawn_local(async move {
             let events = server.events().unwrap();
-            if let Err(e) = ProxyStream::new(cx.data, &server, events).process().await {
+            let mut stream = ProxyStream::new(cx.data, &server, events);
+            if let Some(early_data) = early_data {
+                stream.buffer.put_slice(&early_data);
+            }
+            if let Err(e) = stream.process().await {
                 console_log!("[tunnel]: {}", e);
+                stream.close(CLOSE_PROTOCOL_ERROR, "invalid request");
             }
+            stream.close(CLOSE_NORMAL, "done");
         });
     
-        Response::from_websocket(client)
+        let mut response = Response::from_websocket(client)?;
+        if let Some(ws_protocol) = ws_protocol {
+            response.headers_mut().set("Sec-WebSocket-Protocol", &ws_protocol)?;
+        }
+        Ok(response)
     } else {
         Response::from_html("hi from wasm!")
     }
@@ -107,7 +220,14 @@ fn link(_: Request, cx: RouteContext<Config>) -> Result<Response> {
     }
 
     let host = cx.data.host.to_string();
-    let uuid = cx.data.uuid.to_string();
+    let user = cx
+        .data
+        .active_users()
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::RustError("no active user".to_string()))?;
+    let uuid = user.id.to_string();
+    let password = user.password();
 
     let vmess_link = {
         let config = json!({
@@ -117,20 +237,28 @@ fn link(_: Request, cx: RouteContext<Config>) -> Result<Response> {
             "port": "80",
             "id": uuid,
             "aid": "0",
-            "scy": "zero",
+            "scy": "auto",
             "net": "ws",
             "type": "none",
             "host": host,
-            "path": "/KR",
+            "path": "/vmess/KR?ed=2048",
             "tls": "",
             "sni": "",
             "alpn": ""}
         );
         format!("vmess://{}", URL_SAFE.encode(config.to_string()))
     };
-    let vless_link = format!("vless://{uuid}@{host}:443?encryption=none&type=ws&host={host}&path=%2FKR&security=tls&sni={host}#siren vless");
-    let trojan_link = format!("trojan://{uuid}@{host}:443?encryption=none&type=ws&host={host}&path=%2FKR&security=tls&sni={host}#siren trojan");
-    let ss_link = format!("ss://{}@{host}:443?plugin=v2ray-plugin%3Btls%3Bmux%3D0%3Bmode%3Dwebsocket%3Bpath%3D%2FKR%3Bhost%3D{host}#siren ss", URL_SAFE.encode(format!("none:{uuid}")));
+    let vless_link = format!("vless://{uuid}@{host}:443?encryption=none&type=ws&host={host}&path=%2Fvless%2FKR%3Fed%3D2048&security=tls&sni={host}#siren vless");
+    let trojan_link = format!("trojan://{password}@{host}:443?encryption=none&type=ws&host={host}&path=%2Ftrojan%2FKR%3Fed%3D2048&security=tls&sni={host}#siren trojan");
+    let ss_method = cx.data.ss_method.name();
+    let ss_userinfo = if cx.data.ss_method.is_2022() {
+        // SIP002: 2022 edition keys are percent-encoded instead of base64 encoded
+        let psk = password.replace('+', "%2B").replace('/', "%2F").replace('=', "%3D");
+        format!("{ss_method}:{psk}")
+    } else {
+        URL_SAFE.encode(format!("{ss_method}:{password}"))
+    };
+    let ss_link = format!("ss://{ss_userinfo}@{host}:443?plugin=v2ray-plugin%3Btls%3Bmux%3D0%3Bmode%3Dwebsocket%3Bpath%3D%2Fss%2FKR%3Fed%3D2048%3Bhost%3D{host}#siren ss");
     
     Response::from_json(&Link {
         links: [
@@ -0,0 +1,11 @@
+use bytes::BytesMut;
+use worker::*;
+
+// chunked body encoding applied by ProxyStream once a protocol header is parsed
+pub trait Codec {
+    // decode one chunk from the front of src, None if src doesn't hold a full chunk yet.
+    // an empty chunk marks the end of the stream.
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>>;
+
+    fn encode(&mut self, data: &[u8], dst: &mut BytesMut) -> Result<()>;
+}
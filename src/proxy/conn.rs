@@ -1,21 +1,81 @@
 
-use crate::config::Config;
+use crate::codec::Codec;
+use crate::common::socket_host;
+use crate::config::{Config, DnsUpstream, Protocol, User};
 use crate::dns::resolve;
 
+use std::net::IpAddr;
 use std::pin::Pin;
+use std::time::Duration;
 use std::task::{Context, Poll};
 
 use bytes::{BufMut, BytesMut};
+use futures_util::future::{pending, select, Either};
 use futures_util::Stream;
 use pin_project_lite::pin_project;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use worker::*;
 
+// read size while waiting for the upstream's first bytes
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+
+// https://www.rfc-editor.org/rfc/rfc6455#section-7.4.1
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+pub const CLOSE_BAD_GATEWAY: u16 = 1014;
+
+// how long the client gets to send a complete protocol header
+pub const DETECT_TIMEOUT_MS: u64 = 5000;
+
+// outbound data is coalesced into websocket messages of about this size
+const WRITE_COALESCE_SIZE: usize = 32 * 1024;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Detection {
+    Match,
+    NoMatch,
+    // too few bytes to tell yet
+    NeedMore,
+}
+
+enum Detected {
+    Protocol(Protocol),
+    NeedMore,
+    Unknown,
+}
+
+fn send_write_buffer(ws: &WebSocket, write_buffer: &mut BytesMut) -> Result<()> {
+    if write_buffer.is_empty() {
+        return Ok(());
+    }
+    ws.send_with_bytes(write_buffer.split())
+}
+
+fn close_websocket(ws: &WebSocket, write_buffer: &mut BytesMut, closed: &mut bool, code: u16, reason: &str) {
+    if *closed {
+        return;
+    }
+    *closed = true;
+    if let Err(e) = send_write_buffer(ws, write_buffer) {
+        console_error!("error flushing websocket: {}", e);
+    }
+    if let Err(e) = ws.close(Some(code), Some(reason)) {
+        console_error!("error closing websocket: {}", e);
+    }
+}
+
 pin_project! {
     pub struct ProxyStream<'a> {
         pub config: Config,
+        pub user: Option<User>,
         pub ws: &'a WebSocket,
         pub buffer: BytesMut,
+        pub codec: Option<Box<dyn Codec>>,
+        pub decoded: BytesMut,
+        pub write_buffer: BytesMut,
+        pub closed: bool,
         #[pin]
         pub events: EventStream<'a>,
     }
@@ -27,11 +87,20 @@ impl<'a> ProxyStream<'a> {
 
         Self {
             config,
+            user: None,
             ws,
             buffer,
+            codec: None,
+            decoded: BytesMut::new(),
+            write_buffer: BytesMut::new(),
+            closed: false,
             events,
         }
     }
+
+    pub fn set_codec(&mut self, codec: Box<dyn Codec>) {
+        self.codec = Some(codec);
+    }
     
     pub async fn fill_buffer_until(&mut self, n: usize) -> std::io::Result<()> {
         use futures_util::StreamExt;
@@ -47,7 +116,7 @@ impl<'a> ProxyStream<'a> {
                     break;
                 }
                 Some(Err(e)) => {
-                    return Err(std::io::Error::new(std::io::ErrorKind::Other, e.to_string()));
+                    return Err(std::io::Error::other(e.to_string()));
                 }
                 None => {
                     break;
@@ -58,79 +127,301 @@ impl<'a> ProxyStream<'a> {
         Ok(())
     }
 
+    // one codec chunk, for protocols that carry a datagram per chunk. None at end of stream.
+    pub async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let codec = self
+                .codec
+                .as_mut()
+                .ok_or_else(|| Error::RustError("no codec installed".to_string()))?;
+            if let Some(chunk) = codec.decode(&mut self.buffer)? {
+                return Ok((!chunk.is_empty()).then_some(chunk));
+            }
+
+            let received = self.buffer.len();
+            self.fill_buffer_until(received + 1).await?;
+            if self.buffer.len() == received {
+                return Ok(None);
+            }
+        }
+    }
+
+    // waits for the next message until the deadline (ms since epoch).
+    // false if nothing arrived because the client closed or the deadline passed.
+    pub async fn read_more(&mut self, deadline: u64) -> Result<bool> {
+        let remaining = deadline.saturating_sub(Date::now().as_millis());
+        let received = self.buffer.len();
+        let more = Box::pin(self.fill_buffer_until(received + 1));
+        let timeout = Delay::from(Duration::from_millis(remaining));
+        let read = match select(more, timeout).await {
+            Either::Left((result, _)) => Some(result),
+            Either::Right(_) => None,
+        };
+        match read {
+            Some(result) => {
+                result?;
+                Ok(self.buffer.len() > received)
+            }
+            None => Ok(false),
+        }
+    }
+
     pub fn peek_buffer(&self, n: usize) -> &[u8] {
         let len = self.buffer.len().min(n);
         &self.buffer[..len]
     }
 
+    pub fn close(&mut self, code: u16, reason: &str) {
+        close_websocket(self.ws, &mut self.write_buffer, &mut self.closed, code, reason);
+    }
+
+    pub fn reject(&mut self, reason: &str) -> Error {
+        console_error!("[auth] rejected {} from {}", reason, self.config.client_ip);
+        self.close(CLOSE_POLICY_VIOLATION, reason);
+        Error::RustError(reason.to_string())
+    }
+
+    pub fn authenticate(&mut self, user: User) {
+        console_log!("authenticated user {} ({}) from {}", user.label, user.id, self.config.client_ip);
+        self.user = Some(user);
+    }
+
+    // every protocol is checked against what has arrived so far. a credentialed
+    // match wins right away, otherwise more data is read until each protocol
+    // either matched, failed, or the client stopped sending.
+    fn detect_protocol(&self, exhausted: bool) -> Detected {
+        let checks = [
+            (Protocol::Vless, self.detect_vless()),
+            (Protocol::Trojan, self.detect_trojan()),
+            (Protocol::Vmess, self.detect_vmess()),
+            (Protocol::Shadowsocks, self.detect_shadowsocks()),
+        ];
+        let checks = checks.iter().filter(|(protocol, _)| self.config.is_enabled(*protocol));
+        if let Some((protocol, _)) = checks.clone().find(|(_, detection)| *detection == Detection::Match) {
+            return Detected::Protocol(*protocol);
+        }
+        // a complete plain shadowsocks address doesn't wait for the other protocols,
+        // the client may send nothing more until the upstream answers
+        let plain_shadowsocks = self.config.is_enabled(Protocol::Shadowsocks);
+        if plain_shadowsocks && self.has_plain_shadowsocks_header() {
+            return Detected::Protocol(Protocol::Shadowsocks);
+        }
+        if !exhausted && checks.clone().any(|(_, detection)| *detection == Detection::NeedMore) {
+            return Detected::NeedMore;
+        }
+        if plain_shadowsocks && self.is_plain_shadowsocks() {
+            return Detected::Protocol(Protocol::Shadowsocks);
+        }
+        Detected::Unknown
+    }
+
     pub async fn process(&mut self) -> Result<()> {
-        self.fill_buffer_until(62).await?;
-        let peeked_buffer = self.peek_buffer(62);
+        if let Some(protocol) = self.config.protocol {
+            if !self.config.is_enabled(protocol) {
+                return Err(self.reject("protocol disabled"));
+            }
+            return self.dispatch(protocol).await;
+        }
 
-        if peeked_buffer[0] == 0 {
-            console_log!("VLESS detected!");
-            self.process_vless().await
-        } else if peeked_buffer[0] == 1 || peeked_buffer[0] == 3 {
-            console_log!("Shadowsocks detected!");
-            self.process_shadowsocks().await
-        } else if peeked_buffer[56] == 13 && peeked_buffer[57] == 10 {
-            console_log!("Trojan detected!");
-            self.process_trojan().await
-        } else {
-            console_log!("Vmess detected!");
-            self.process_vmess().await
+        let deadline = Date::now().as_millis() + DETECT_TIMEOUT_MS;
+        let mut exhausted = false;
+        let protocol = loop {
+            match self.detect_protocol(exhausted) {
+                Detected::Protocol(protocol) => break protocol,
+                Detected::Unknown if self.buffer.is_empty() => {
+                    return Err(Error::RustError("connection closed before any data".to_string()));
+                }
+                Detected::Unknown => return Err(self.reject("unknown protocol")),
+                Detected::NeedMore => {}
+            }
+
+            // the client closed or timed out without sending a full header
+            exhausted = !self.read_more(deadline).await?;
+        };
+        self.dispatch(protocol).await
+    }
+
+    async fn dispatch(&mut self, protocol: Protocol) -> Result<()> {
+        match protocol {
+            Protocol::Vless => {
+                console_log!("VLESS detected!");
+                self.process_vless().await
+            }
+            Protocol::Trojan => {
+                console_log!("Trojan detected!");
+                self.process_trojan().await
+            }
+            Protocol::Vmess => {
+                console_log!("Vmess detected!");
+                self.process_vmess().await
+            }
+            Protocol::Shadowsocks => {
+                console_log!("Shadowsocks detected!");
+                self.process_shadowsocks().await
+            }
         }
     }
 
+    // dial the destination, then fall back to the proxy ip if the dial fails or
+    // the upstream closes before answering. client data is replayed on fallback.
     pub async fn handle_tcp_outbound(&mut self, addr: String, port: u16) -> Result<()> {
+        let resolved = match resolve_addr(&addr).await {
+            Ok(ip) => Some(ip),
+            Err(e) => {
+                console_error!("{}", e);
+                None
+            }
+        };
+        if let Some(ip) = resolved.filter(|ip| self.config.is_cloudflare(ip)) {
+            console_log!("{} is a cloudflare address, skipping direct dial", ip);
+        }
+        let targets = self.config.dial_targets(resolved, port);
+
+        let mut replay = Vec::new();
+        for (target_addr, target_port) in targets {
+            match self.relay_tcp(target_addr, target_port, &mut replay).await {
+                Ok(true) => return Ok(()),
+                Ok(false) => console_log!("upstream closed before responding"),
+                Err(e) => console_error!("error handling tcp: {}", e),
+            }
+        }
+
+        self.close(CLOSE_BAD_GATEWAY, "upstream unreachable");
+        Err(Error::RustError("all upstreams failed".to_string()))
+    }
+
+    // returns false if the upstream failed or closed before sending any data
+    async fn relay_tcp(&mut self, addr: String, port: u16, replay: &mut Vec<u8>) -> Result<bool> {
         console_log!("connecting to upstream {}:{}", addr, port);
 
-        let addr_ip = if addr.parse::<std::net::IpAddr>().is_ok() {
-            addr.clone()
-        } else {
-            resolve(&addr).await.map_err(|e| worker::Error::RustError(format!("resolve failed: {e}")))?
-        };
+        let mut remote_socket = connect(&addr, port).await?;
 
-        let mut remote_socket = Socket::builder().connect(addr_ip, port).map_err(|e| {
-            Error::RustError(e.to_string())
-        })?;
+        if !replay.is_empty() {
+            remote_socket.write_all(replay).await?;
+        }
 
-        remote_socket.opened().await.map_err(|e| {
-            Error::RustError(e.to_string())
-        })?;
+        // keep the client data until the upstream answers
+        let mut client_buf = vec![0u8; RELAY_BUFFER_SIZE];
+        let mut upstream_buf = vec![0u8; RELAY_BUFFER_SIZE];
+        loop {
+            // stop reading the client once the replay buffer is full, until the upstream answers
+            let client_read = Box::pin(if replay.len() < self.config.max_buffer_size {
+                Either::Left(self.read(&mut client_buf))
+            } else {
+                Either::Right(pending())
+            });
+            let upstream_read = Box::pin(remote_socket.read(&mut upstream_buf));
+            match select(client_read, upstream_read).await {
+                Either::Left((Ok(0), _)) => {
+                    // client is done sending, half-close the upstream and wait for its answer
+                    remote_socket.shutdown().await?;
+                    if let Err(e) = tokio::io::copy(&mut remote_socket, self).await {
+                        console_error!("error relaying tcp: {}", e);
+                        self.close(CLOSE_INTERNAL_ERROR, "upstream error");
+                    }
+                    self.shutdown().await?;
+                    return Ok(true);
+                }
+                Either::Left((Ok(n), _)) => {
+                    replay.extend_from_slice(&client_buf[..n]);
+                    if remote_socket.write_all(&client_buf[..n]).await.is_err() {
+                        return Ok(false);
+                    }
+                }
+                Either::Left((Err(e), _)) => {
+                    console_error!("error reading from client: {}", e);
+                    return Ok(true);
+                }
+                Either::Right((Ok(0), _)) | Either::Right((Err(_), _)) => return Ok(false),
+                Either::Right((Ok(n), _)) => {
+                    self.write_all(&upstream_buf[..n]).await?;
+                    // copy_bidirectional only flushes what it wrote itself
+                    self.flush().await?;
+                    break;
+                }
+            }
+        }
 
-        tokio::io::copy_bidirectional(self, &mut remote_socket)
-            .await
-            .map_err(|e| {
-                Error::RustError(e.to_string())
-            })?;
-        Ok(())
+        // shuts down the websocket once the upstream is done, and the upstream write side once the client is
+        if let Err(e) = tokio::io::copy_bidirectional(self, &mut remote_socket).await {
+            console_error!("error relaying tcp: {}", e);
+            self.close(CLOSE_INTERNAL_ERROR, "upstream error");
+        }
+        Ok(true)
     }
 
     pub async fn handle_udp_outbound(&mut self) -> Result<()> {
         let mut buff = vec![0u8; 65535];
         let n = self.read(&mut buff).await?;
-        let data = &buff[..n];
-
-        let is_dns_query = data.len() >= 12 && (data[2] & 0x80) == 0;
 
-        if is_dns_query {
-            match crate::dns::doh(data).await {
-                Ok(resp) => {
-                    self.write(&resp).await?;
-                    return Ok(());
-                }
-                Err(e) => {
-                    console_error!("DoH gagal: {}", e);
-                }
-            }
+        if let Some(resp) = forward_dns(&self.config.dns_upstream, &buff[..n]).await {
+            self.write_all(&resp).await?;
+            self.flush().await?;
         }
+        Ok(())
+    }
+}
 
+fn is_dns_query(packet: &[u8]) -> bool {
+    packet.len() >= 12 && (packet[2] & 0x80) == 0
+}
+
+// workers can't send udp, so only dns queries are answered, through the configured upstream
+pub async fn forward_dns(upstream: &DnsUpstream, packet: &[u8]) -> Option<Vec<u8>> {
+    if !is_dns_query(packet) {
         console_log!("UDP bukan DNS, atau format tidak valid");
-        Ok(())
+        return None;
+    }
+
+    match crate::dns::query(upstream, packet).await {
+        Ok(resp) => Some(resp),
+        Err(e) => {
+            console_error!("dns query failed: {}", e);
+            None
+        }
+    }
+}
+
+// asks the server the client addressed over tcp, most resolvers serve both
+pub async fn forward_dns_to(upstream: &DnsUpstream, addr: &str, port: u16, packet: &[u8]) -> Option<Vec<u8>> {
+    if !is_dns_query(packet) {
+        console_log!("dropping non-dns udp packet to {}:{}", addr, port);
+        return None;
+    }
+
+    let host = match addr.parse::<IpAddr>() {
+        Ok(ip) => socket_host(&ip),
+        Err(_) => addr.to_string(),
+    };
+    match crate::dns::query_tcp(&host, port, false, packet).await {
+        Ok(resp) => Some(resp),
+        Err(e) => {
+            console_error!("dns over tcp to {}:{} failed, using the upstream: {}", addr, port, e);
+            forward_dns(upstream, packet).await
+        }
     }
 }
 
+pub async fn resolve_addr(addr: &str) -> Result<IpAddr> {
+    // ipv6 literals may come bracketed
+    match addr.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => Ok(ip),
+        Err(_) => resolve(addr).await.map_err(|e| worker::Error::RustError(format!("resolve failed: {e}"))),
+    }
+}
+
+// resolves and waits for the socket to open, so an unreachable target fails here.
+// the write side is left for the caller to shut down once the client is done.
+pub async fn connect(addr: &str, port: u16) -> Result<Socket> {
+    let ip = resolve_addr(addr).await?;
+    let socket = Socket::builder()
+        .allow_half_open(true)
+        .connect(socket_host(&ip), port)
+        .map_err(|e| Error::RustError(e.to_string()))?;
+    socket.opened().await.map_err(|e| Error::RustError(e.to_string()))?;
+    Ok(socket)
+}
+
 impl<'a> AsyncRead for ProxyStream<'a> {
     fn poll_read(
         self: Pin<&mut Self>,
@@ -140,15 +431,45 @@ impl<'a> AsyncRead for ProxyStream<'a> {
         let mut this = self.project();
 
         loop {
-            let size = std::cmp::min(this.buffer.len(), buf.remaining());
+            let mut end_of_stream = false;
+            if let Some(codec) = this.codec.as_mut() {
+                while let Some(chunk) = codec
+                    .decode(this.buffer)
+                    .map_err(|e| std::io::Error::other(e.to_string()))?
+                {
+                    if chunk.is_empty() {
+                        end_of_stream = true;
+                        break;
+                    }
+                    this.decoded.put_slice(&chunk);
+                }
+            }
+
+            let readable = if this.codec.is_some() {
+                &mut *this.decoded
+            } else {
+                &mut *this.buffer
+            };
+            let size = std::cmp::min(readable.len(), buf.remaining());
             if size > 0 {
-                buf.put_slice(&this.buffer.split_to(size));
+                buf.put_slice(&readable.split_to(size));
+                return Poll::Ready(Ok(()));
+            }
+            if end_of_stream {
                 return Poll::Ready(Ok(()));
             }
 
+            // a partial chunk that big can only come from a broken or hostile client
+            if this.buffer.len() >= this.config.max_buffer_size {
+                return Poll::Ready(Err(std::io::Error::other("buffer limit exceeded")));
+            }
+
             match this.events.as_mut().poll_next(cx) {
                 Poll::Ready(Some(Ok(WebsocketEvent::Message(msg)))) => {
-                    msg.bytes().iter().for_each(|x| this.buffer.put_slice(&x));
+                    msg.bytes().iter().for_each(|x| this.buffer.put_slice(x));
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    return Poll::Ready(Err(std::io::Error::other(e.to_string())));
                 }
                 Poll::Pending => return Poll::Pending,
                 _ => return Poll::Ready(Ok(())),
@@ -163,19 +484,37 @@ impl<'a> AsyncWrite for ProxyStream<'a> {
         _: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<tokio::io::Result<usize>> {
-        return Poll::Ready(
-            self.ws
-                .send_with_bytes(buf)
+        let this = self.project();
+
+        let mut result = match this.codec.as_mut() {
+            Some(codec) => codec.encode(buf, this.write_buffer),
+            None => {
+                this.write_buffer.put_slice(buf);
+                Ok(())
+            }
+        };
+        if result.is_ok() && this.write_buffer.len() >= WRITE_COALESCE_SIZE {
+            result = send_write_buffer(this.ws, this.write_buffer);
+        }
+
+        Poll::Ready(
+            result
                 .map(|_| buf.len())
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string())),
-        );
+                .map_err(|e| std::io::Error::other(e.to_string())),
+        )
     }
 
     fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
-        Poll::Ready(Ok(()))
+        let this = self.project();
+        Poll::Ready(
+            send_write_buffer(this.ws, this.write_buffer)
+                .map_err(|e| std::io::Error::other(e.to_string())),
+        )
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
-        unimplemented!()
+        let this = self.project();
+        close_websocket(this.ws, this.write_buffer, this.closed, CLOSE_NORMAL, "upstream closed");
+        Poll::Ready(Ok(()))
     }
 }
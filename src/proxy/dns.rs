@@ -3,13 +3,28 @@ use anyhow::{Result, anyhow, Context}; // Menambahkan import Context
 use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
 use reqwest::Client;
 use serde::Deserialize;
-use std::net::Ipv4Addr;
-use tokio::time::{sleep, Duration};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
 
+use futures_util::future::{select, Either};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 // Pastikan impor console_error sudah benar
-use worker::console_error;
+use worker::{console_error, Delay, SecureTransport, Socket};
 
-pub async fn doh(req_wireformat: &[u8]) -> Result<Vec<u8>> {
+use crate::config::DnsUpstream;
+
+// how long a tcp dns server gets to answer
+const TCP_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub async fn query(upstream: &DnsUpstream, req_wireformat: &[u8]) -> Result<Vec<u8>> {
+    match upstream {
+        DnsUpstream::Doh(url) => doh(url, req_wireformat).await,
+        DnsUpstream::Dot(host, port) => query_tcp(host, *port, true, req_wireformat).await,
+        DnsUpstream::Tcp(host, port) => query_tcp(host, *port, false, req_wireformat).await,
+    }
+}
+
+pub async fn doh(url: &str, req_wireformat: &[u8]) -> Result<Vec<u8>> {
     let mut headers = HeaderMap::new();
     headers.insert(
         CONTENT_TYPE,
@@ -22,7 +37,7 @@ pub async fn doh(req_wireformat: &[u8]) -> Result<Vec<u8>> {
 
     for _ in 0..retry_count {
         let response = client
-            .post("https://1.1.1.1/dns-query")
+            .post(url)
             .headers(headers.clone())
             .body(req_wireformat.to_vec())
             .send()
@@ -36,16 +51,50 @@ pub async fn doh(req_wireformat: &[u8]) -> Result<Vec<u8>> {
             return Ok(response.to_vec());
         } else {
             console_error!("Received empty response, retrying...");
-            sleep(Duration::from_secs(1)).await;
+            Delay::from(Duration::from_secs(1)).await;
         }
     }
 
     Err(anyhow!("Failed to get valid response after retrying"))
 }
 
+// dns over tcp, each message is prefixed with its length (rfc 1035 4.2.2).
+// dns over tls is the same framing inside tls (rfc 7858).
+pub async fn query_tcp(host: &str, port: u16, tls: bool, req_wireformat: &[u8]) -> Result<Vec<u8>> {
+    let secure_transport = if tls { SecureTransport::On } else { SecureTransport::Off };
+    let mut socket = Socket::builder()
+        .secure_transport(secure_transport)
+        .connect(host, port)
+        .map_err(|e| anyhow!("failed to connect to {host}:{port}: {e}"))?;
+
+    let query = async {
+        socket.opened().await.map_err(|e| anyhow!(e.to_string()))?;
+        let mut frame = Vec::with_capacity(2 + req_wireformat.len());
+        frame.extend_from_slice(&(req_wireformat.len() as u16).to_be_bytes());
+        frame.extend_from_slice(req_wireformat);
+        socket.write_all(&frame).await?;
+        socket.flush().await?;
+
+        let len = socket.read_u16().await?;
+        let mut resp = vec![0u8; len as _];
+        socket.read_exact(&mut resp).await?;
+        Ok::<_, anyhow::Error>(resp)
+    };
+    let resp = match select(Box::pin(query), Delay::from(TCP_QUERY_TIMEOUT)).await {
+        Either::Left((resp, _)) => resp,
+        Either::Right(_) => Err(anyhow!("timed out waiting for {host}:{port}")),
+    };
+
+    if let Err(e) = socket.close().await {
+        console_error!("error closing dns socket: {}", e);
+    }
+    resp
+}
+
 #[derive(Deserialize)]
 struct DoHAnswer {
-    Answer: Option<Vec<DNSAnswer>>, // Mengganti Answer menjadi answer
+    #[serde(rename = "Answer")]
+    answer: Option<Vec<DNSAnswer>>,
 }
 
 #[derive(Deserialize)]
@@ -53,7 +102,7 @@ struct DNSAnswer {
     data: String,
 }
 
-pub async fn resolve(domain: &str) -> Result<String> {
+pub async fn resolve(domain: &str) -> Result<IpAddr> {
     let url = format!(
         "https://dns.google/resolve?name={}&type=A",
         domain
@@ -65,10 +114,10 @@ pub async fn resolve(domain: &str) -> Result<String> {
     let parsed: DoHAnswer = serde_json::from_str(&resp)
         .context("Failed to parse DNS response for A record")?;
 
-    if let Some(answers) = parsed.Answer {  // Mengganti Answer menjadi answer
+    if let Some(answers) = parsed.answer {
         for ans in answers {
             if let Ok(ip) = ans.data.parse::<Ipv4Addr>() {
-                return Ok(ip.to_string());
+                return Ok(IpAddr::V4(ip));
             }
         }
     }
@@ -82,10 +131,10 @@ pub async fn resolve(domain: &str) -> Result<String> {
     let parsed_aaaa: DoHAnswer = serde_json::from_str(&resp_aaaa)
         .context("Failed to parse DNS response for AAAA record")?;
 
-    if let Some(answers) = parsed_aaaa.Answer {  // Mengganti Answer menjadi answer
+    if let Some(answers) = parsed_aaaa.answer {
         for ans in answers {
-            if let Ok(ip) = ans.data.parse::<std::net::Ipv6Addr>() {
-                return Ok(ip.to_string());
+            if let Ok(ip) = ans.data.parse::<Ipv6Addr>() {
+                return Ok(IpAddr::V6(ip));
             }
         }
     }
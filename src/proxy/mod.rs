@@ -3,5 +3,7 @@ pub mod vless;
 pub mod trojan;
 pub mod shadowsocks;
 pub mod dns;
+pub mod codec;
+pub mod mux;
 pub mod conn;
 pub use conn::*;
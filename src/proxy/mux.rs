@@ -0,0 +1,514 @@
+use super::{connect, forward_dns, resolve_addr, ProxyStream};
+
+use crate::common::{encode_addr, parse_addr, VLESS_ADDR};
+use crate::config::DnsUpstream;
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::Cursor;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use bytes::{Buf, BytesMut};
+use futures_util::future::{abortable, select, AbortHandle, Either};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use tokio::io::{split, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use worker::*;
+
+// https://xtls.github.io/en/development/protocols/muxcool.html
+const STATUS_NEW: u8 = 0x01;
+const STATUS_KEEP: u8 = 0x02;
+const STATUS_END: u8 = 0x03;
+const STATUS_KEEP_ALIVE: u8 = 0x04;
+
+const OPTION_DATA: u8 = 0x01;
+const OPTION_ERROR: u8 = 0x02;
+
+const NETWORK_TCP: u8 = 0x01;
+const NETWORK_UDP: u8 = 0x02;
+
+// read size for each sub-connection
+const MUX_CHUNK_SIZE: usize = 8 * 1024;
+
+// +-------------------+-----------------+-------------------+-------------------+
+// |  Metadata Length  |    Metadata     |    Data Length    |       Data        |
+// +-------------------+-----------------+-------------------+-------------------+
+// |      2 Bytes      |    L Bytes      |      2 Bytes      |     X Bytes       |
+// +-------------------+-----------------+-------------------+-------------------+
+//
+// metadata starts with the session id (2 bytes), status and option (1 byte each).
+// data length and data are only present when the option has OPTION_DATA set.
+pub struct Frame {
+    pub id: u16,
+    pub status: u8,
+    pub option: u8,
+    // the rest of the metadata, e.g. the target of a new session
+    pub extra: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(id: u16, status: u8, option: u8, data: &[u8]) -> Self {
+        Self {
+            id,
+            status,
+            option,
+            extra: Vec::new(),
+            data: data.to_vec(),
+        }
+    }
+
+    // None until the whole frame has arrived
+    pub fn decode(src: &mut BytesMut) -> Result<Option<Self>> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+        let meta_len = u16::from_be_bytes([src[0], src[1]]) as usize;
+        if meta_len < 4 {
+            return Err(Error::RustError("mux: metadata too short".to_string()));
+        }
+        let mut frame_len = 2 + meta_len;
+        if src.len() < frame_len {
+            return Ok(None);
+        }
+
+        let option = src[5];
+        if option & OPTION_DATA != 0 {
+            if src.len() < frame_len + 2 {
+                return Ok(None);
+            }
+            frame_len += 2 + u16::from_be_bytes([src[frame_len], src[frame_len + 1]]) as usize;
+            if src.len() < frame_len {
+                return Ok(None);
+            }
+        }
+
+        let mut frame = src.split_to(frame_len);
+        frame.advance(2);
+        let id = frame.get_u16();
+        let status = frame.get_u8();
+        frame.advance(1);
+        let extra = frame.split_to(meta_len - 4).to_vec();
+        if option & OPTION_DATA != 0 {
+            frame.advance(2);
+        }
+
+        Ok(Some(Self {
+            id,
+            status,
+            option,
+            extra,
+            data: frame.to_vec(),
+        }))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let meta_len = 4 + self.extra.len();
+        let mut buf = Vec::with_capacity(2 + meta_len + 2 + self.data.len());
+        buf.extend_from_slice(&(meta_len as u16).to_be_bytes());
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.push(self.status);
+        buf.push(self.option);
+        buf.extend_from_slice(&self.extra);
+        if self.option & OPTION_DATA != 0 {
+            buf.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&self.data);
+        }
+        buf
+    }
+}
+
+// client data waiting for an upstream. every session's queue counts against
+// max_buffer_size until the data is written or dropped with its session.
+struct Queued {
+    data: Vec<u8>,
+    total: Rc<Cell<usize>>,
+}
+
+impl Queued {
+    // None when it would take the mux past the limit
+    fn new(data: Vec<u8>, total: &Rc<Cell<usize>>, limit: usize) -> Option<Self> {
+        let size = total.get() + data.len();
+        if size > limit {
+            return None;
+        }
+        total.set(size);
+        Some(Self { data, total: total.clone() })
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.total.set(self.total.get() - self.data.len());
+    }
+}
+
+enum Session {
+    // data for the upstream is queued and written by the session's own future,
+    // so a slow upstream only holds up its session and not the whole mux
+    Tcp {
+        queue: UnboundedSender<Queued>,
+        // handed to the writer once the socket opens
+        pending: Option<UnboundedReceiver<Queued>>,
+        // resolving, connecting or reading, whichever is in flight
+        task: AbortHandle,
+    },
+    // packets go to the target of the new frame unless a keep frame names another (xudp)
+    Udp {
+        addr: String,
+        port: u16,
+    },
+}
+
+enum Event {
+    // the target of a new session was looked up, None if that failed
+    Resolved(u16, Option<IpAddr>, u16),
+    Connected(u16, Result<Socket>),
+    // whatever the sub-connection read next, empty at eof
+    Read(u16, ReadHalf<Socket>, std::io::Result<Vec<u8>>),
+    // the writer drained its queue and shut the upstream down, or a write failed
+    Written(u16, std::io::Result<()>),
+    // dns answer for a udp session, from the address the query was sent to
+    Reply(u16, String, u16, Option<Vec<u8>>),
+    // the session was ended by the client
+    Aborted,
+}
+
+type SessionFuture = Pin<Box<dyn Future<Output = Event>>>;
+
+fn abortable_session(session: impl Future<Output = Event> + 'static) -> (SessionFuture, AbortHandle) {
+    let (session, handle) = abortable(session);
+    (Box::pin(async move { session.await.unwrap_or(Event::Aborted) }), handle)
+}
+
+fn resolve_session(id: u16, addr: String, port: u16) -> (SessionFuture, AbortHandle) {
+    abortable_session(async move {
+        let ip = match resolve_addr(&addr).await {
+            Ok(ip) => Some(ip),
+            Err(e) => {
+                console_error!("mux: {}", e);
+                None
+            }
+        };
+        Event::Resolved(id, ip, port)
+    })
+}
+
+// tries the targets in order until one opens
+fn connect_session(id: u16, targets: Vec<(String, u16)>) -> (SessionFuture, AbortHandle) {
+    abortable_session(async move {
+        let mut result = Err(Error::RustError("no target to dial".to_string()));
+        for (host, port) in targets {
+            result = connect(&host, port).await;
+            if result.is_ok() {
+                break;
+            }
+        }
+        Event::Connected(id, result)
+    })
+}
+
+fn read_session(id: u16, mut reader: ReadHalf<Socket>) -> (SessionFuture, AbortHandle) {
+    abortable_session(async move {
+        let mut buf = vec![0u8; MUX_CHUNK_SIZE];
+        let result = reader.read(&mut buf).await.map(|n| buf[..n].to_vec());
+        Event::Read(id, reader, result)
+    })
+}
+
+// runs until the session is dropped and everything queued has been written
+fn write_session(id: u16, mut writer: WriteHalf<Socket>, mut queue: UnboundedReceiver<Queued>) -> SessionFuture {
+    Box::pin(async move {
+        let result = async {
+            while let Some(queued) = queue.recv().await {
+                writer.write_all(&queued.data).await?;
+            }
+            writer.shutdown().await
+        };
+        Event::Written(id, result.await)
+    })
+}
+
+// workers can't send udp, dns is answered by the upstream and anything else is dropped
+fn query_session(upstream: DnsUpstream, id: u16, addr: String, port: u16, packet: Vec<u8>) -> SessionFuture {
+    Box::pin(async move {
+        let resp = forward_dns(&upstream, &packet).await;
+        Event::Reply(id, addr, port, resp)
+    })
+}
+
+// +-------------------+-------------------+-------------------+-------------------+
+// |      Network      |       Port        |   Address Type    |      Address      |
+// +-------------------+-------------------+-------------------+-------------------+
+// |      1 Byte       |      2 Bytes      |      1 Byte       |     Variable      |
+// +-------------------+-------------------+-------------------+-------------------+
+async fn parse_target(extra: &mut Cursor<Vec<u8>>) -> Result<(u8, String, u16)> {
+    let network = extra.read_u8().await?;
+    let port = extra.read_u16().await?;
+    let addr = parse_addr(extra, VLESS_ADDR).await?;
+    Ok((network, addr, port))
+}
+
+fn encode_target(network: u8, addr: &str, port: u16) -> Vec<u8> {
+    [&[network][..], &port.to_be_bytes(), &encode_addr(addr, VLESS_ADDR)].concat()
+}
+
+impl<'a> ProxyStream<'a> {
+    // vless and vmess send no address with the mux command, every new frame names
+    // its own target. each sub-connection gets its own socket. frames from the client
+    // and every session's connect, reads and writes are raced, so one slow or idle
+    // session doesn't hold up the others.
+    pub async fn process_mux(&mut self) -> Result<()> {
+        let mut sessions: HashMap<u16, Session> = HashMap::new();
+        let mut events = FuturesUnordered::new();
+        let queued = Rc::new(Cell::new(0));
+        let mut incoming = BytesMut::new();
+        let mut buf = vec![0u8; MUX_CHUNK_SIZE];
+
+        loop {
+            while let Some(frame) = Frame::decode(&mut incoming)? {
+                self.handle_mux_frame(frame, &mut sessions, &mut events, &queued).await?;
+            }
+            self.flush().await?;
+
+            // reading the client is cancel safe, so losing the race drops nothing
+            let event = match select(Box::pin(self.read(&mut buf)), events.next()).await {
+                Either::Left((n, _)) => Either::Left(n?),
+                Either::Right((event, _)) => Either::Right(event),
+            };
+            match event {
+                Either::Left(0) => break,
+                Either::Left(n) => incoming.extend_from_slice(&buf[..n]),
+                // nothing in flight, only the client can make progress
+                Either::Right(None) => {
+                    let n = self.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    incoming.extend_from_slice(&buf[..n]);
+                }
+                Either::Right(Some(event)) => self.handle_mux_event(event, &mut sessions, &mut events).await?,
+            }
+        }
+
+        // dropping the queues lets the writers flush what's left and shut down
+        for (_, session) in sessions.drain() {
+            if let Session::Tcp { task, .. } = session {
+                task.abort();
+            }
+        }
+        while events.next().await.is_some() {}
+        Ok(())
+    }
+
+    async fn handle_mux_event(
+        &mut self,
+        event: Event,
+        sessions: &mut HashMap<u16, Session>,
+        events: &mut FuturesUnordered<SessionFuture>,
+    ) -> Result<()> {
+        match event {
+            Event::Resolved(id, ip, port) => {
+                if let Some(Session::Tcp { task, .. }) = sessions.get_mut(&id) {
+                    let (connect, handle) = connect_session(id, self.config.dial_targets(ip, port));
+                    events.push(connect);
+                    *task = handle;
+                }
+            }
+            Event::Connected(id, Ok(socket)) => {
+                if let Some(Session::Tcp { pending, task, .. }) = sessions.get_mut(&id) {
+                    if let Some(queue) = pending.take() {
+                        let (reader, writer) = split(socket);
+                        let (read, handle) = read_session(id, reader);
+                        events.push(read);
+                        events.push(write_session(id, writer, queue));
+                        *task = handle;
+                    }
+                }
+            }
+            Event::Connected(id, Err(e)) => {
+                console_error!("mux session {} failed to connect: {}", id, e);
+                if sessions.remove(&id).is_some() {
+                    self.write_all(&Frame::new(id, STATUS_END, OPTION_ERROR, &[]).encode()).await?;
+                }
+            }
+            Event::Read(id, reader, Ok(data)) if !data.is_empty() => {
+                if let Some(Session::Tcp { task, .. }) = sessions.get_mut(&id) {
+                    let (read, handle) = read_session(id, reader);
+                    events.push(read);
+                    *task = handle;
+                    self.write_all(&Frame::new(id, STATUS_KEEP, OPTION_DATA, &data).encode()).await?;
+                }
+            }
+            Event::Read(id, _, result) => {
+                let option = match result {
+                    Ok(_) => 0,
+                    Err(e) => {
+                        console_error!("mux session {} failed: {}", id, e);
+                        OPTION_ERROR
+                    }
+                };
+                if let Some(Session::Tcp { .. }) = sessions.remove(&id) {
+                    self.write_all(&Frame::new(id, STATUS_END, option, &[]).encode()).await?;
+                }
+            }
+            Event::Written(id, Err(e)) => {
+                // a closed queue means this writer belongs to the session under that id
+                // and not to a newer one reusing it
+                if let Some(Session::Tcp { queue, task, .. }) = sessions.get(&id) {
+                    if queue.is_closed() {
+                        console_error!("mux session {} write failed: {}", id, e);
+                        task.abort();
+                        sessions.remove(&id);
+                        self.write_all(&Frame::new(id, STATUS_END, OPTION_ERROR, &[]).encode()).await?;
+                    }
+                }
+            }
+            Event::Reply(id, addr, port, Some(resp)) => {
+                if sessions.contains_key(&id) {
+                    let mut frame = Frame::new(id, STATUS_KEEP, OPTION_DATA, &resp);
+                    frame.extra = encode_target(NETWORK_UDP, &addr, port);
+                    self.write_all(&frame.encode()).await?;
+                }
+            }
+            Event::Written(_, Ok(())) | Event::Reply(_, _, _, None) | Event::Aborted => {}
+        }
+        Ok(())
+    }
+
+    async fn handle_mux_frame(
+        &mut self,
+        frame: Frame,
+        sessions: &mut HashMap<u16, Session>,
+        events: &mut FuturesUnordered<SessionFuture>,
+        queued: &Rc<Cell<usize>>,
+    ) -> Result<()> {
+        let limit = self.config.max_buffer_size;
+        match frame.status {
+            STATUS_NEW => {
+                // xudp appends an 8 byte global id, only needed to share udp ports
+                let (network, addr, port) = parse_target(&mut Cursor::new(frame.extra)).await?;
+                let session = match network {
+                    NETWORK_TCP => {
+                        let (queue, pending) = unbounded_channel();
+                        if !frame.data.is_empty() {
+                            let Some(data) = Queued::new(frame.data, queued, limit) else {
+                                console_error!("mux session {} exceeded the buffer limit", frame.id);
+                                self.write_all(&Frame::new(frame.id, STATUS_END, OPTION_ERROR, &[]).encode()).await?;
+                                return Ok(());
+                            };
+                            let _ = queue.send(data);
+                        }
+                        let (resolve, task) = resolve_session(frame.id, addr, port);
+                        events.push(resolve);
+                        Session::Tcp { queue, pending: Some(pending), task }
+                    }
+                    NETWORK_UDP => {
+                        if !frame.data.is_empty() {
+                            events.push(query_session(self.config.dns_upstream.clone(), frame.id, addr.clone(), port, frame.data));
+                        }
+                        Session::Udp { addr, port }
+                    }
+                    _ => {
+                        console_log!("mux: unsupported network {} for {}:{}", network, addr, port);
+                        self.write_all(&Frame::new(frame.id, STATUS_END, OPTION_ERROR, &[]).encode()).await?;
+                        return Ok(());
+                    }
+                };
+                if let Some(Session::Tcp { task, .. }) = sessions.insert(frame.id, session) {
+                    task.abort();
+                }
+            }
+            STATUS_KEEP => match sessions.get_mut(&frame.id) {
+                // a failed writer is reported through its event
+                Some(Session::Tcp { queue, task, .. }) if !frame.data.is_empty() => match Queued::new(frame.data, queued, limit) {
+                    Some(data) => {
+                        let _ = queue.send(data);
+                    }
+                    // the upstream isn't keeping up, give up on this session rather than the mux
+                    None => {
+                        console_error!("mux session {} exceeded the buffer limit", frame.id);
+                        task.abort();
+                        sessions.remove(&frame.id);
+                        self.write_all(&Frame::new(frame.id, STATUS_END, OPTION_ERROR, &[]).encode()).await?;
+                    }
+                },
+                Some(Session::Udp { addr, port }) => {
+                    let (addr, port) = if frame.extra.is_empty() {
+                        (addr.clone(), *port)
+                    } else {
+                        let (_, addr, port) = parse_target(&mut Cursor::new(frame.extra)).await?;
+                        (addr, port)
+                    };
+                    if !frame.data.is_empty() {
+                        events.push(query_session(self.config.dns_upstream.clone(), frame.id, addr, port, frame.data));
+                    }
+                }
+                Some(Session::Tcp { .. }) | None => {}
+            },
+            STATUS_END => {
+                if let Some(Session::Tcp { task, .. }) = sessions.remove(&frame.id) {
+                    task.abort();
+                }
+            }
+            STATUS_KEEP_ALIVE => {}
+            status => {
+                return Err(Error::RustError(format!("mux: unknown frame status {status}")));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mux_frame() {
+        let mut new = Frame::new(7, STATUS_NEW, OPTION_DATA, b"hello");
+        new.extra = vec![NETWORK_TCP, 0x01, 0xbb, 0x02, 0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e'];
+        let encoded = [new.encode(), Frame::new(7, STATUS_END, 0, &[]).encode()].concat();
+
+        // partial frames wait for more data
+        let mut src = BytesMut::from(&encoded[..10]);
+        assert!(Frame::decode(&mut src).unwrap().is_none());
+        assert_eq!(src.len(), 10);
+
+        let mut src = BytesMut::from(&encoded[..]);
+        let frame = Frame::decode(&mut src).unwrap().unwrap();
+        assert_eq!((frame.id, frame.status, frame.option), (7, STATUS_NEW, OPTION_DATA));
+        assert_eq!(frame.extra, new.extra);
+        assert_eq!(frame.data, b"hello");
+
+        let frame = Frame::decode(&mut src).unwrap().unwrap();
+        assert_eq!((frame.id, frame.status), (7, STATUS_END));
+        assert!(frame.data.is_empty());
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_queued_limit() {
+        let total = Rc::new(Cell::new(0));
+        let first = Queued::new(vec![0; 6], &total, 10).unwrap();
+        assert!(Queued::new(vec![0; 5], &total, 10).is_none());
+        assert_eq!(total.get(), 6);
+
+        // written or dropped data frees its share of the limit
+        drop(first);
+        assert_eq!(total.get(), 0);
+        assert!(Queued::new(vec![0; 10], &total, 10).is_some());
+    }
+
+    #[test]
+    fn test_xudp_target() {
+        // new udp frame with a trailing global id
+        let mut extra = encode_target(NETWORK_UDP, "8.8.8.8", 53);
+        extra.extend_from_slice(&[0xaa; 8]);
+        let target = crate::common::block_on(parse_target(&mut Cursor::new(extra))).unwrap();
+        assert_eq!(target, (NETWORK_UDP, "8.8.8.8".to_string(), 53));
+    }
+}
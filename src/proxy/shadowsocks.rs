@@ -1,31 +1,400 @@
-use super::ProxyStream;
+use super::{Detection, ProxyStream, DETECT_TIMEOUT_MS};
 
+use crate::codec::Codec;
+use crate::common::aead::{AeadCipher, AEAD_NONCE_SIZE, AEAD_TAG_SIZE};
+use crate::common::replay::ReplayCache;
+use crate::common::unix_time;
+use crate::config::User;
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use bytes::{BufMut, BytesMut};
+use hkdf::Hkdf;
+use once_cell::sync::Lazy;
+use md5::{Digest, Md5};
+use sha1::Sha1;
 use tokio::io::AsyncReadExt;
 use worker::*;
 
+// https://shadowsocks.org/doc/aead.html
+const SUBKEY_INFO: &[u8] = b"ss-subkey";
+const MAX_PAYLOAD_SIZE: usize = 0x3fff;
+
+// https://github.com/Shadowsocks-NET/shadowsocks-specs/blob/main/2022-1-shadowsocks-2022-edition.md
+const SESSION_SUBKEY_CONTEXT: &str = "shadowsocks 2022 session subkey";
+const MAX_PAYLOAD_SIZE_2022: usize = 0xffff;
+const HEADER_TYPE_CLIENT: u8 = 0;
+const HEADER_TYPE_SERVER: u8 = 1;
+const TIMESTAMP_WINDOW: u64 = 30;
+const SALT_WINDOW: u64 = 60;
+
+// request salts seen within the window by this isolate, 2022 edition only
+static SALT_CACHE: Lazy<ReplayCache> = Lazy::new(ReplayCache::new);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Method {
+    None,
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20IetfPoly1305,
+    Blake3Aes128Gcm,
+    Blake3Aes256Gcm,
+}
+
+impl Method {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "none" | "plain" => Ok(Self::None),
+            "aes-128-gcm" => Ok(Self::Aes128Gcm),
+            "aes-256-gcm" => Ok(Self::Aes256Gcm),
+            "chacha20-ietf-poly1305" => Ok(Self::ChaCha20IetfPoly1305),
+            "2022-blake3-aes-128-gcm" => Ok(Self::Blake3Aes128Gcm),
+            "2022-blake3-aes-256-gcm" => Ok(Self::Blake3Aes256Gcm),
+            _ => Err(Error::RustError(format!("unsupported shadowsocks method: {name}"))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Aes128Gcm => "aes-128-gcm",
+            Self::Aes256Gcm => "aes-256-gcm",
+            Self::ChaCha20IetfPoly1305 => "chacha20-ietf-poly1305",
+            Self::Blake3Aes128Gcm => "2022-blake3-aes-128-gcm",
+            Self::Blake3Aes256Gcm => "2022-blake3-aes-256-gcm",
+        }
+    }
+
+    // salt has the same size as the key
+    fn key_size(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Aes128Gcm | Self::Blake3Aes128Gcm => 16,
+            Self::Aes256Gcm | Self::ChaCha20IetfPoly1305 | Self::Blake3Aes256Gcm => 32,
+        }
+    }
+
+    pub fn is_2022(&self) -> bool {
+        matches!(self, Self::Blake3Aes128Gcm | Self::Blake3Aes256Gcm)
+    }
+
+    // plaintext size of the first chunk, which is the fixed-length header in the 2022 edition
+    fn header_size(&self) -> usize {
+        if self.is_2022() {
+            1 + 8 + 2
+        } else {
+            2
+        }
+    }
+
+    fn cipher(&self, key: &[u8]) -> AeadCipher {
+        match self {
+            Self::Aes128Gcm | Self::Blake3Aes128Gcm => AeadCipher::aes_128_gcm(key),
+            Self::Aes256Gcm | Self::Blake3Aes256Gcm => AeadCipher::aes_256_gcm(key),
+            _ => AeadCipher::chacha20_poly1305(key),
+        }
+    }
+
+    pub fn password_to_key(&self, password: &str) -> Result<Vec<u8>> {
+        // the 2022 edition takes a base64 psk of exactly the key size
+        if self.is_2022() {
+            return STANDARD
+                .decode(password)
+                .ok()
+                .filter(|key| key.len() == self.key_size())
+                .ok_or_else(|| {
+                    Error::RustError(format!("{} needs a base64 psk of {} bytes", self.name(), self.key_size()))
+                });
+        }
+
+        // EVP_BytesToKey with md5, as in the original shadowsocks implementation
+        let mut key = Vec::new();
+        let mut prev: Vec<u8> = Vec::new();
+        while key.len() < self.key_size() {
+            prev = crate::md5!(&prev, password.as_bytes()).to_vec();
+            key.extend_from_slice(&prev);
+        }
+        key.truncate(self.key_size());
+        Ok(key)
+    }
+
+    fn session_key(&self, key: &[u8], salt: &[u8]) -> Result<Vec<u8>> {
+        if self.is_2022() {
+            if key.len() != self.key_size() {
+                return Err(Error::RustError("invalid shadowsocks 2022 psk".to_string()));
+            }
+            let mut material = key.to_vec();
+            material.extend_from_slice(salt);
+            let subkey = blake3::derive_key(SESSION_SUBKEY_CONTEXT, &material);
+            return Ok(subkey[..self.key_size()].to_vec());
+        }
+
+        let mut subkey = vec![0u8; self.key_size()];
+        Hkdf::<Sha1>::new(Some(salt), key)
+            .expand(SUBKEY_INFO, &mut subkey)
+            .map_err(|e| Error::RustError(e.to_string()))?;
+        Ok(subkey)
+    }
+
+    fn subkey(&self, key: &[u8], salt: &[u8]) -> Result<AeadCipher> {
+        Ok(self.cipher(&self.session_key(key, salt)?))
+    }
+}
+
+// +--------+-----------+-----------------------------+
+// |  Type  | Timestamp | Variable-length header size |
+// +--------+-----------+-----------------------------+
+// |   1B   |    8B     |             2B              |
+// +--------+-----------+-----------------------------+
+fn decode_request_header(header: &[u8], now: u64) -> Result<usize> {
+    let timestamp = u64::from_be_bytes(header[1..9].try_into().unwrap());
+    if header[0] != HEADER_TYPE_CLIENT || now.abs_diff(timestamp) > TIMESTAMP_WINDOW {
+        return Err(Error::RustError("shadowsocks: invalid request header".to_string()));
+    }
+    Ok(u16::from_be_bytes([header[9], header[10]]) as usize)
+}
+
+// +--------+-----------+----------------+--------+
+// |  Type  | Timestamp |  Request salt  | Length |
+// +--------+-----------+----------------+--------+
+// |   1B   |    8B     |    16/32B      |   2B   |
+// +--------+-----------+----------------+--------+
+// the codec appends the length of the first chunk
+fn response_header(now: u64, request_salt: &[u8]) -> Vec<u8> {
+    let mut header = vec![HEADER_TYPE_SERVER];
+    header.extend_from_slice(&now.to_be_bytes());
+    header.extend_from_slice(request_salt);
+    header
+}
+
+// aead cipher with the little endian nonce counter
+struct AeadStream {
+    cipher: AeadCipher,
+    nonce: [u8; AEAD_NONCE_SIZE],
+}
+
+impl AeadStream {
+    fn new(cipher: AeadCipher) -> Self {
+        Self {
+            cipher,
+            nonce: [0u8; AEAD_NONCE_SIZE],
+        }
+    }
+
+    fn next_nonce(&mut self) -> [u8; AEAD_NONCE_SIZE] {
+        let nonce = self.nonce;
+        for b in self.nonce.iter_mut() {
+            *b = b.wrapping_add(1);
+            if *b != 0 {
+                break;
+            }
+        }
+        nonce
+    }
+
+    fn seal(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        self.cipher.encrypt(&nonce, data)
+    }
+
+    fn open(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        self.cipher.decrypt(&nonce, data)
+    }
+}
+
+// +--------------+---------------+--------------+------------+
+// | *DataLength  | DataLength TAG|    *Data     |  Data TAG  |
+// +--------------+---------------+--------------+------------+
+// |      2       |      16       |   Variable   |     16     |
+// +--------------+---------------+--------------+------------+
+pub struct ShadowsocksCodec {
+    reader: AeadStream,
+    writer: AeadStream,
+    max_payload_size: usize,
+    // response salt, sent in front of the first chunk
+    salt: Option<Vec<u8>>,
+    // 2022 response header fields, sent in front of the first length
+    header: Option<Vec<u8>>,
+    // payload length of a chunk whose length was read but payload isn't complete yet
+    pending: Option<usize>,
+}
+
+impl ShadowsocksCodec {
+    fn new(reader: AeadCipher, writer: AeadCipher, salt: Vec<u8>) -> Self {
+        Self {
+            reader: AeadStream::new(reader),
+            writer: AeadStream::new(writer),
+            max_payload_size: MAX_PAYLOAD_SIZE,
+            salt: Some(salt),
+            header: None,
+            pending: None,
+        }
+    }
+}
+
+impl Codec for ShadowsocksCodec {
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+        let length = match self.pending {
+            Some(length) => length,
+            None => {
+                if src.len() < 2 + AEAD_TAG_SIZE {
+                    return Ok(None);
+                }
+                let length = self.reader.open(&src.split_to(2 + AEAD_TAG_SIZE))?;
+                let length = (u16::from_be_bytes([length[0], length[1]]) as usize) & self.max_payload_size;
+                self.pending = Some(length);
+                length
+            }
+        };
+        if src.len() < length + AEAD_TAG_SIZE {
+            return Ok(None);
+        }
+
+        self.pending = None;
+        self.reader.open(&src.split_to(length + AEAD_TAG_SIZE)).map(Some)
+    }
+
+    fn encode(&mut self, data: &[u8], dst: &mut BytesMut) -> Result<()> {
+        if let Some(salt) = self.salt.take() {
+            dst.put_slice(&salt);
+        }
+        for chunk in data.chunks(self.max_payload_size) {
+            let mut length = self.header.take().unwrap_or_default();
+            length.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            let length = self.writer.seal(&length)?;
+            let payload = self.writer.seal(chunk)?;
+            dst.put_slice(&length);
+            dst.put_slice(&payload);
+        }
+        Ok(())
+    }
+}
+
+// size of the socks address and port that start a plain shadowsocks stream,
+// None until the domain length is in
+fn plain_header_size(peeked: &[u8]) -> Option<usize> {
+    match peeked {
+        [1, ..] => Some(1 + 4 + 2),
+        [3, len, ..] => Some(1 + 1 + *len as usize + 2),
+        [4, ..] => Some(1 + 16 + 2),
+        _ => None,
+    }
+}
+
 impl <'a> ProxyStream<'a> {
+    // find the user whose key opens the first chunk after the salt
+    fn find_shadowsocks_user(&self, method: Method) -> Option<(User, AeadCipher)> {
+        let salt_size = method.key_size();
+        let first_chunk_size = method.header_size() + AEAD_TAG_SIZE;
+        let peeked = self.peek_buffer(salt_size + first_chunk_size);
+        if method == Method::None || peeked.len() < salt_size + first_chunk_size {
+            return None;
+        }
+
+        let (salt, length) = peeked.split_at(salt_size);
+        self.config.active_users().into_iter().find_map(|user| {
+            let key = method.password_to_key(&user.password()).ok()?;
+            let cipher = method.subkey(&key, salt).ok()?;
+            cipher.decrypt(&[0u8; AEAD_NONCE_SIZE], length).ok()?;
+            Some((user, cipher))
+        })
+    }
+
+    // aead traffic starts with a random salt, so it only matches once the first chunk decrypts
+    pub fn detect_shadowsocks(&self) -> Detection {
+        let method = self.config.ss_method;
+        if method == Method::None {
+            return Detection::NoMatch;
+        }
+        if self.buffer.len() < method.key_size() + method.header_size() + AEAD_TAG_SIZE {
+            return Detection::NeedMore;
+        }
+
+        match self.find_shadowsocks_user(method) {
+            Some(_) => Detection::Match,
+            None => Detection::NoMatch,
+        }
+    }
+
+    // plain shadowsocks has no credential, only the socks address type to go by
+    pub fn is_plain_shadowsocks(&self) -> bool {
+        self.config.ss_method == Method::None
+            && matches!(self.peek_buffer(1), [1] | [3] | [4])
+    }
+
+    // true once the socks address and port of a plain shadowsocks stream are in
+    pub fn has_plain_shadowsocks_header(&self) -> bool {
+        self.config.ss_method == Method::None
+            && plain_header_size(self.peek_buffer(2)).is_some_and(|size| self.buffer.len() >= size)
+    }
+
     pub async fn process_shadowsocks(&mut self) -> Result<()> {
+        let method = self.config.ss_method;
+        if method != Method::None {
+            // a pinned protocol skips detection, so the first chunk may not be here yet
+            let deadline = Date::now().as_millis() + DETECT_TIMEOUT_MS;
+            let needed = method.key_size() + method.header_size() + AEAD_TAG_SIZE;
+            while self.buffer.len() < needed && self.read_more(deadline).await? {}
+
+            let (user, cipher) = match self.find_shadowsocks_user(method) {
+                Some(matched) => matched,
+                None => return Err(self.reject("shadowsocks: invalid password")),
+            };
+
+            let mut salt = vec![0u8; method.key_size()];
+            self.read_exact(&mut salt).await?;
+
+            let key = method.password_to_key(&user.password())?;
+            let mut response_salt = vec![0u8; method.key_size()];
+            getrandom::getrandom(&mut response_salt).map_err(|e| Error::RustError(e.to_string()))?;
+
+            let writer = method.subkey(&key, &response_salt)?;
+            let mut codec = ShadowsocksCodec::new(cipher, writer, response_salt);
+
+            if method.is_2022() {
+                let mut header = vec![0u8; method.header_size() + AEAD_TAG_SIZE];
+                self.read_exact(&mut header).await?;
+                let header = codec.reader.open(&header)?;
+
+                let now = unix_time();
+                let variable_header_size = match decode_request_header(&header, now) {
+                    Ok(size) => size,
+                    Err(_) => return Err(self.reject("shadowsocks: invalid request header")),
+                };
+                if !SALT_CACHE.insert(&salt, now + SALT_WINDOW, now) {
+                    return Err(self.reject("shadowsocks: replayed salt"));
+                }
+
+                // the variable-length header is decoded as the first payload chunk
+                codec.pending = Some(variable_header_size);
+                codec.max_payload_size = MAX_PAYLOAD_SIZE_2022;
+                codec.header = Some(response_header(now, &salt));
+            }
+
+            self.authenticate(user);
+            self.set_codec(Box::new(codec));
+        }
+
         // read port and address
-        let remote_addr = crate::common::parse_addr(self).await?;
+        let remote_addr = crate::common::parse_addr(self, crate::common::SOCKS_ADDR).await?;
         let remote_port = {
             let mut port = [0u8; 2];
             self.read_exact(&mut port).await?;
             ((port[0] as u16) << 8) | (port[1] as u16)
         };
-        
+
+        // the 2022 variable-length header ends with padding before the initial payload
+        if method.is_2022() {
+            let padding_length = self.read_u16().await?;
+            let mut padding = vec![0u8; padding_length as _];
+            self.read_exact(&mut padding).await?;
+        }
+
         let is_tcp = true; // difficult to detect udp packet from shadowsocks
-        
+
         if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
-
-            // send header
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
-                }
+            if let Err(e) = self.handle_tcp_outbound(remote_addr, remote_port).await {
+                console_error!("error handling tcp: {}", e)
             }
         } else {
             if let Err(e) = self.handle_udp_outbound().await {
@@ -36,3 +405,179 @@ impl <'a> ProxyStream<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::from_hex;
+
+    #[test]
+    fn test_password_to_key() {
+        // EVP_BytesToKey(md5, "password") from openssl
+        let key = Method::Aes256Gcm.password_to_key("password").unwrap();
+        assert_eq!(
+            key,
+            [
+                0x5f, 0x4d, 0xcc, 0x3b, 0x5a, 0xa7, 0x65, 0xd6, 0x1d, 0x83, 0x27, 0xde, 0xb8, 0x82,
+                0xcf, 0x99, 0x2b, 0x95, 0x99, 0x0a, 0x91, 0x51, 0x37, 0x4a, 0xbd, 0x8f, 0xf8, 0xc5,
+                0xa7, 0xa0, 0xfe, 0x08
+            ]
+        );
+        assert_eq!(Method::Aes128Gcm.password_to_key("password").unwrap(), key[..16]);
+    }
+
+    fn new_codec(method: Method, password: &str, salt: &[u8]) -> ShadowsocksCodec {
+        let key = method.password_to_key(password).unwrap();
+        let cipher = || method.subkey(&key, salt).unwrap();
+        ShadowsocksCodec::new(cipher(), cipher(), salt.to_vec())
+    }
+
+    #[test]
+    fn test_plain_header_size() {
+        assert_eq!(plain_header_size(&[1, 1, 1, 1, 1, 0, 80]), Some(7));
+        assert_eq!(plain_header_size(b"\x03\x07example\x00\x50"), Some(11));
+        assert_eq!(plain_header_size(&[4]), Some(19));
+        // the domain length is needed before the size is known
+        assert_eq!(plain_header_size(&[3]), None);
+        assert_eq!(plain_header_size(&[2, 1, 1, 1, 1, 0, 80]), None);
+        assert_eq!(plain_header_size(&[]), None);
+    }
+
+    #[test]
+    fn test_nonce_counter() {
+        let mut stream = AeadStream::new(AeadCipher::aes_128_gcm(&[0u8; 16]));
+        stream.nonce[0] = 0xff;
+        assert_eq!(stream.next_nonce(), [0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        // little endian, the carry goes to the next byte
+        assert_eq!(stream.next_nonce(), [0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_aead_codec() {
+        // salt, then sealed length and payload per write, computed with python's
+        // cryptography package (EVP_BytesToKey, HKDF-SHA1 "ss-subkey", nonce 0, 1, 2, ...)
+        let salt: Vec<u8> = (0..16).collect();
+        let mut codec = new_codec(Method::Aes128Gcm, "password", &salt);
+        let mut encoded = BytesMut::new();
+        codec.encode(b"hello", &mut encoded).unwrap();
+        codec.encode(b"world", &mut encoded).unwrap();
+        assert_eq!(
+            encoded[..],
+            from_hex(
+                "000102030405060708090a0b0c0d0e0f5c2b27a26ad0cdf9cd7aa4f3c851b134b4b9947477b58a2f87d1\
+                 affe84b78de924b5f222d5ec8cf7d21e1c0c17caadfde232c6e55dc00f4c2ce1bc576a8639a278fe76\
+                 55803c1942dc4b962947b1"
+            )
+        );
+
+        let salt: Vec<u8> = (0..32).collect();
+        let mut encoded = BytesMut::new();
+        new_codec(Method::ChaCha20IetfPoly1305, "password", &salt)
+            .encode(b"hello", &mut encoded)
+            .unwrap();
+        assert_eq!(
+            encoded[..],
+            from_hex(
+                "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1fad4d5c2599d42f6d9b\
+                 26804b82a3b96dc584e8adc7498c0ff41f578989fe0c5ded753038d91134"
+            )
+        );
+    }
+
+    #[test]
+    fn test_2022_session_key() {
+        // BLAKE3 derive_key("shadowsocks 2022 session subkey", psk || salt), from an
+        // independent python implementation checked against the BLAKE3 test vectors
+        let method = Method::Blake3Aes128Gcm;
+        let psk = method.password_to_key("AAECAwQFBgcICQoLDA0ODw==").unwrap();
+        assert_eq!(psk, (0..16).collect::<Vec<u8>>());
+        let salt: Vec<u8> = (16..32).collect();
+        assert_eq!(
+            method.session_key(&psk, &salt).unwrap(),
+            from_hex("bc32fb8d5205f7b84f9691dfb9f04ff3")
+        );
+        assert_eq!(
+            method.subkey(&psk, &salt).unwrap().encrypt(&[0u8; AEAD_NONCE_SIZE], b"hello").unwrap(),
+            from_hex("9e4e2ec056690262780793f2687cdeecd0963bc048")
+        );
+
+        // the psk has to be exactly the key size
+        assert!(method.password_to_key("password").is_err());
+        assert!(method.session_key(&[], &salt).is_err());
+    }
+
+    #[test]
+    fn test_2022_request_header() {
+        let now = 1_700_000_000u64;
+        let header = |kind: u8, timestamp: u64| {
+            let mut header = vec![kind];
+            header.extend_from_slice(&timestamp.to_be_bytes());
+            header.extend_from_slice(&300u16.to_be_bytes());
+            header
+        };
+
+        assert_eq!(decode_request_header(&header(HEADER_TYPE_CLIENT, now), now).unwrap(), 300);
+        assert!(decode_request_header(&header(HEADER_TYPE_CLIENT, now + TIMESTAMP_WINDOW), now).is_ok());
+        assert!(decode_request_header(&header(HEADER_TYPE_CLIENT, now - TIMESTAMP_WINDOW - 1), now).is_err());
+        assert!(decode_request_header(&header(HEADER_TYPE_SERVER, now), now).is_err());
+    }
+
+    #[test]
+    fn test_2022_response() {
+        let method = Method::Blake3Aes128Gcm;
+        let psk: Vec<u8> = (0..16).collect();
+        let request_salt: Vec<u8> = (16..32).collect();
+        let response_salt: Vec<u8> = (32..48).collect();
+        let writer = || method.subkey(&psk, &response_salt).unwrap();
+
+        let mut codec = ShadowsocksCodec::new(writer(), writer(), response_salt.clone());
+        codec.max_payload_size = MAX_PAYLOAD_SIZE_2022;
+        codec.header = Some(response_header(1_700_000_000, &request_salt));
+        let mut encoded = BytesMut::new();
+        codec.encode(b"hello", &mut encoded).unwrap();
+        assert_eq!(
+            encoded[..],
+            from_hex(
+                "202122232425262728292a2b2c2d2e2fcfd439ccd5cb580032874d1521cc11d623310888444d4ba6dd\
+                 1abe45ba451e33368d692b622909e55df22376b8fe840531a992726ee53528aa397878c34ada2c"
+            )
+        );
+
+        // salt, then type, timestamp, request salt and length sealed as one chunk
+        let mut reader = AeadStream::new(writer());
+        let header = reader.open(&encoded[16..16 + 27 + AEAD_TAG_SIZE]).unwrap();
+        assert_eq!(header[0], HEADER_TYPE_SERVER);
+        assert_eq!(header[1..9], 1_700_000_000u64.to_be_bytes());
+        assert_eq!(header[9..25], request_salt[..]);
+        assert_eq!(header[25..], 5u16.to_be_bytes());
+        assert_eq!(reader.open(&encoded[16 + 27 + AEAD_TAG_SIZE..]).unwrap(), b"hello");
+
+        // the header only goes in front of the first chunk
+        let mut encoded = BytesMut::new();
+        codec.encode(b"again", &mut encoded).unwrap();
+        assert_eq!(encoded.len(), 2 + AEAD_TAG_SIZE + 5 + AEAD_TAG_SIZE);
+    }
+
+    #[test]
+    fn test_aead_codec_roundtrip() {
+        let salt: Vec<u8> = (0..32).collect();
+        let mut codec = new_codec(Method::Aes256Gcm, "password", &salt);
+        let data: Vec<u8> = (0..40000).map(|i| i as u8).collect();
+        let mut encoded = BytesMut::new();
+        codec.encode(&data, &mut encoded).unwrap();
+        assert_eq!(encoded[..32], salt[..]);
+
+        // fed a few bytes at a time, chunks come out once complete
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for piece in encoded[32..].chunks(1000) {
+            src.extend_from_slice(piece);
+            while let Some(chunk) = codec.decode(&mut src).unwrap() {
+                assert!(chunk.len() <= MAX_PAYLOAD_SIZE);
+                decoded.extend_from_slice(&chunk);
+            }
+        }
+        assert_eq!(decoded, data);
+        assert!(src.is_empty());
+    }
+}
@@ -1,13 +1,56 @@
-use super::ProxyStream;
+use super::{forward_dns_to, Detection, ProxyStream};
 
-use tokio::io::AsyncReadExt;
+use crate::common::{constant_time_eq, encode_addr, parse_addr, SOCKS_ADDR};
+use crate::config::User;
+
+use std::io::ErrorKind;
+
+use sha2::{Digest, Sha224};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use worker::*;
 
+// hex(sha224(password)) and crlf
+const TROJAN_MIN_HEADER_SIZE: usize = 56 + 2;
+
 impl <'a> ProxyStream<'a> {
+    fn find_trojan_user(&self, password_hash: &[u8]) -> Option<User> {
+        let password_hash = password_hash.to_ascii_lowercase();
+        self.config.active_users().into_iter().find(|user| {
+            let expected_hash = Sha224::digest(user.password().as_bytes())
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>();
+            constant_time_eq(&password_hash, expected_hash.as_bytes())
+        })
+    }
+
+    pub fn detect_trojan(&self) -> Detection {
+        let peeked = self.peek_buffer(TROJAN_MIN_HEADER_SIZE);
+        if !peeked.iter().take(56).all(u8::is_ascii_hexdigit) {
+            return Detection::NoMatch;
+        }
+        if peeked.len() < TROJAN_MIN_HEADER_SIZE {
+            return Detection::NeedMore;
+        }
+        if peeked[56..] != *b"\r\n" {
+            return Detection::NoMatch;
+        }
+
+        match self.find_trojan_user(&peeked[..56]) {
+            Some(_) => Detection::Match,
+            None => Detection::NoMatch,
+        }
+    }
+
     pub async fn process_trojan(&mut self) -> Result<()> {
-        // ignore user_id
-        let mut _user_id = [0u8; 56];
-        self.read_exact(&mut _user_id).await?;
+        // read hex(sha224(password))
+        let mut password_hash = [0u8; 56];
+        self.read_exact(&mut password_hash).await?;
+
+        match self.find_trojan_user(&password_hash) {
+            Some(user) => self.authenticate(user),
+            None => return Err(self.reject("trojan: invalid password")),
+        }
 
         // remove crlf
         self.read_u16().await?;
@@ -17,7 +60,7 @@ impl <'a> ProxyStream<'a> {
         let is_tcp = network_type == 1;
 
         // read port and address
-        let remote_addr = crate::common::parse_addr(self).await?;
+        let remote_addr = parse_addr(self, SOCKS_ADDR).await?;
         let remote_port = {
             let mut port = [0u8; 2];
             self.read_exact(&mut port).await?;
@@ -28,23 +71,47 @@ impl <'a> ProxyStream<'a> {
         self.read_u16().await?;
 
         if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
-
-            // send header
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
-                }
+            if let Err(e) = self.handle_tcp_outbound(remote_addr, remote_port).await {
+                console_error!("error handling tcp: {}", e)
             }
         } else {
-            if let Err(e) = self.handle_udp_outbound().await {
+            if let Err(e) = self.handle_trojan_udp().await {
                 console_error!("error handling udp: {}", e)
             }
         }
 
         Ok(())
     }
+
+    // +------+----------+----------+--------+---------+----------+
+    // | ATYP | DST.ADDR | DST.PORT | Length |  CRLF   | Payload  |
+    // +------+----------+----------+--------+---------+----------+
+    // |  1   | Variable |    2     |   2    | X'0D0A' | Variable |
+    // +------+----------+----------+--------+---------+----------+
+    async fn handle_trojan_udp(&mut self) -> Result<()> {
+        loop {
+            let remote_addr = match parse_addr(self, SOCKS_ADDR).await {
+                Ok(addr) => addr,
+                Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let remote_port = self.read_u16().await?;
+            let len = self.read_u16().await?;
+            // remove crlf
+            self.read_u16().await?;
+            let mut packet = vec![0u8; len as _];
+            self.read_exact(&mut packet).await?;
+
+            if let Some(resp) = forward_dns_to(&self.config.dns_upstream, &remote_addr, remote_port, &packet).await {
+                // replies come from the address the packet was sent to
+                let mut frame = encode_addr(&remote_addr, SOCKS_ADDR);
+                frame.extend_from_slice(&remote_port.to_be_bytes());
+                frame.extend_from_slice(&(resp.len() as u16).to_be_bytes());
+                frame.extend_from_slice(b"\r\n");
+                frame.extend_from_slice(&resp);
+                self.write_all(&frame).await?;
+                self.flush().await?;
+            }
+        }
+    }
 }
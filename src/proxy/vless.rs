@@ -1,10 +1,37 @@
-use super::ProxyStream;
+use super::{forward_dns_to, Detection, ProxyStream};
+
+use std::io::ErrorKind;
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use uuid::Uuid;
 use worker::*;
 
+const COMMAND_TCP: u8 = 0x01;
+const COMMAND_MUX: u8 = 0x03;
+
+// version and user id
+const VLESS_MIN_HEADER_SIZE: usize = 1 + 16;
+
 impl <'a> ProxyStream<'a> {
+    pub fn detect_vless(&self) -> Detection {
+        let peeked = self.peek_buffer(VLESS_MIN_HEADER_SIZE);
+        match peeked.first() {
+            None => return Detection::NeedMore,
+            Some(0) => {}
+            Some(_) => return Detection::NoMatch,
+        }
+        if peeked.len() < VLESS_MIN_HEADER_SIZE {
+            return Detection::NeedMore;
+        }
+
+        let uuid = Uuid::from_slice(&peeked[1..]).unwrap_or_default();
+        if self.config.active_users().iter().any(|user| user.id == uuid) {
+            Detection::Match
+        } else {
+            Detection::NoMatch
+        }
+    }
+
     pub async fn process_vless(&mut self) -> Result<()> {
         // ignore version
         self.read_u8().await?;
@@ -12,7 +39,11 @@ impl <'a> ProxyStream<'a> {
         // read uuid
         let mut user_id = [0u8; 16];
         self.read_exact(&mut user_id).await?;
-        let _uuid = Uuid::from_bytes(user_id);
+        let uuid = Uuid::from_bytes(user_id);
+        match self.config.active_users().into_iter().find(|user| user.id == uuid) {
+            Some(user) => self.authenticate(user),
+            None => return Err(self.reject("vless: invalid user id")),
+        }
         
         // read protobuf
         let m_len = self.read_u8().await?;
@@ -20,8 +51,17 @@ impl <'a> ProxyStream<'a> {
         self.read_exact(&mut protobuf).await?;
 
         // read instruction
-        let network_type = self.read_u8().await?;
-        let is_tcp = network_type == 1;
+        let command = self.read_u8().await?;
+
+        // send header
+        self.write_all(&[0u8; 2]).await?;
+        self.flush().await?;
+        if command == COMMAND_MUX {
+            if let Err(e) = self.process_mux().await {
+                console_error!("error handling mux: {}", e)
+            }
+            return Ok(());
+        }
 
         // read port and address
         let remote_port = {
@@ -29,27 +69,46 @@ impl <'a> ProxyStream<'a> {
             self.read_exact(&mut port).await?;
             ((port[0] as u16) << 8) | (port[1] as u16)
         };
-        let remote_addr = crate::common::parse_addr(self).await?;
-
-        if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
-
-            // send header
-            self.write(&[0u8; 2]).await?;
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
-                }
+        let remote_addr = crate::common::parse_addr(self, crate::common::VLESS_ADDR).await?;
+
+        if command == COMMAND_TCP {
+            if let Err(e) = self.handle_tcp_outbound(remote_addr, remote_port).await {
+                console_error!("error handling tcp: {}", e)
             }
         } else {
-            if let Err(e) = self.handle_udp_outbound().await {
+            if let Err(e) = self.handle_vless_udp(remote_addr, remote_port).await {
                 console_error!("error handling udp: {}", e)
             }
         }
 
         Ok(())
     }
+
+    // every udp packet, both ways, is prefixed with its length. they all go to the
+    // target in the header, so the replies carry no address
+    //
+    // +-------------------+-------------------+
+    // |      Length       |      Payload      |
+    // +-------------------+-------------------+
+    // |      2 Bytes      |      Length       |
+    // +-------------------+-------------------+
+    async fn handle_vless_udp(&mut self, remote_addr: String, remote_port: u16) -> Result<()> {
+        loop {
+            let len = match self.read_u16().await {
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            let mut packet = vec![0u8; len as _];
+            self.read_exact(&mut packet).await?;
+
+            if let Some(resp) = forward_dns_to(&self.config.dns_upstream, &remote_addr, remote_port, &packet).await {
+                let mut frame = Vec::with_capacity(2 + resp.len());
+                frame.extend_from_slice(&(resp.len() as u16).to_be_bytes());
+                frame.extend_from_slice(&resp);
+                self.write_all(&frame).await?;
+                self.flush().await?;
+            }
+        }
+    }
 }
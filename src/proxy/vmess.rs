@@ -1,31 +1,352 @@
-use super::ProxyStream;
+use super::{forward_dns_to, Detection, ProxyStream};
 
+use crate::codec::Codec;
+use crate::common::aead::{AeadCipher, AEAD_NONCE_SIZE, AEAD_TAG_SIZE};
+use crate::common::replay::ReplayCache;
+use crate::config::User;
 use crate::common::{
-    hash, KDFSALT_CONST_AEAD_RESP_HEADER_IV, KDFSALT_CONST_AEAD_RESP_HEADER_KEY,
+    hash, unix_time, KDFSALT_CONST_AEAD_RESP_HEADER_IV, KDFSALT_CONST_AEAD_RESP_HEADER_KEY,
+    KDFSALT_CONST_AUTH_ID_ENCRYPTION_KEY, KDFSALT_CONST_AUTH_LEN,
     KDFSALT_CONST_AEAD_RESP_HEADER_LEN_IV, KDFSALT_CONST_AEAD_RESP_HEADER_LEN_KEY,
     KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_IV, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY,
     KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV,
     KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY,
 };
 use std::io::Cursor;
-use aes::cipher::KeyInit;
+use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::Aes128;
+use bytes::{Buf, BufMut, BytesMut};
 use aes_gcm::{
     aead::{Aead, Payload},
     Aes128Gcm,
 };
 use md5::{Digest, Md5};
+use once_cell::sync::Lazy;
 use sha2::Sha256;
+use sha3::digest::{ExtendableOutput, XofReader};
+use sha3::{Shake128, Shake128Reader};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use worker::*;
 
+const SECURITY_AES_128_GCM: u8 = 0x03;
+const SECURITY_CHACHA20_POLY1305: u8 = 0x04;
+const SECURITY_NONE: u8 = 0x05;
+const SECURITY_ZERO: u8 = 0x06;
+
+const COMMAND_TCP: u8 = 0x01;
+const COMMAND_UDP: u8 = 0x02;
+const COMMAND_MUX: u8 = 0x03;
+
+const OPTION_CHUNK_STREAM: u8 = 0x01;
+const OPTION_CHUNK_MASKING: u8 = 0x04;
+const OPTION_GLOBAL_PADDING: u8 = 0x08;
+const OPTION_AUTHENTICATED_LENGTH: u8 = 0x10;
+
+// plaintext size of each chunk written back to the client
+const MAX_CHUNK_SIZE: usize = 8192;
+
+// maximum clock drift accepted for the auth id timestamp, same as v2ray
+const AUTH_ID_WINDOW: u64 = 120;
+
+// auth id, encrypted header length and nonce
+const VMESS_MIN_HEADER_SIZE: usize = 16 + 18 + 8;
+
+// auth ids seen within the window by this isolate
+static AUTH_ID_CACHE: Lazy<ReplayCache> = Lazy::new(ReplayCache::new);
+
+// https://github.com/v2fly/v2ray-core/blob/master/proxy/vmess/aead/authid.go
+//
+// +-------------------+-------------------+-------------------+
+// |     Timestamp     |       Random      |       CRC32       |
+// +-------------------+-------------------+-------------------+
+// |      8 Bytes      |      4 Bytes      |      4 Bytes      |
+// +-------------------+-------------------+-------------------+
+fn decode_auth_id(key: &[u8], auth_id: &[u8; 16]) -> Result<u64> {
+    let auth_id_key = &hash::kdf(key, &[KDFSALT_CONST_AUTH_ID_ENCRYPTION_KEY])[..16];
+    let mut block = (*auth_id).into();
+    Aes128::new(auth_id_key.into()).decrypt_block(&mut block);
+
+    let checksum = u32::from_be_bytes([block[12], block[13], block[14], block[15]]);
+    if crc32fast::hash(&block[..12]) != checksum {
+        return Err(Error::RustError("invalid auth id checksum".to_string()));
+    }
+
+    let mut timestamp = [0u8; 8];
+    timestamp.copy_from_slice(&block[..8]);
+    Ok(u64::from_be_bytes(timestamp))
+}
+
+fn decrypt_header_length(key: &[u8], auth_id: &[u8], len: &[u8], nonce: &[u8]) -> Result<u16> {
+    let header_length_key = &hash::kdf(
+        key,
+        &[
+            KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY,
+            auth_id,
+            nonce,
+        ],
+    )[..16];
+    let header_length_nonce = &hash::kdf(
+        key,
+        &[
+            KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV,
+            auth_id,
+            nonce,
+        ],
+    )[..12];
+
+    let payload = Payload {
+        msg: len,
+        aad: auth_id,
+    };
+
+    let len = Aes128Gcm::new(header_length_key.into())
+        .decrypt(header_length_nonce.into(), payload)
+        .map_err(|e| Error::RustError(e.to_string()))?;
+
+    Ok(((len[0] as u16) << 8) | (len[1] as u16))
+}
+
+// one direction of the chunk stream, nonce is count (2 bytes) + iv[2..12]
+struct ChunkCipher {
+    cipher: Option<AeadCipher>,
+    iv: [u8; 16],
+    count: u16,
+}
+
+impl ChunkCipher {
+    fn new(security: u8, key: &[u8], iv: &[u8]) -> Result<Self> {
+        let cipher = match security {
+            SECURITY_AES_128_GCM => Some(AeadCipher::aes_128_gcm(key)),
+            SECURITY_CHACHA20_POLY1305 => {
+                // https://github.com/v2fly/v2ray-core/blob/master/proxy/vmess/encoding/auth.go
+                let first = crate::md5!(key);
+                let second = crate::md5!(&first);
+                let mut chacha_key = [0u8; 32];
+                chacha_key[..16].copy_from_slice(&first);
+                chacha_key[16..].copy_from_slice(&second);
+                Some(AeadCipher::chacha20_poly1305(&chacha_key))
+            }
+            SECURITY_NONE => None,
+            _ => return Err(Error::RustError(format!("unsupported security: {security}"))),
+        };
+
+        let mut chunk_iv = [0u8; 16];
+        chunk_iv.copy_from_slice(iv);
+        Ok(Self {
+            cipher,
+            iv: chunk_iv,
+            count: 0,
+        })
+    }
+
+    fn next_nonce(&mut self) -> [u8; AEAD_NONCE_SIZE] {
+        let mut nonce = [0u8; AEAD_NONCE_SIZE];
+        nonce[..2].copy_from_slice(&self.count.to_be_bytes());
+        nonce[2..].copy_from_slice(&self.iv[2..12]);
+        self.count = self.count.wrapping_add(1);
+        nonce
+    }
+
+    fn seal(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(&nonce, data),
+            None => Ok(data.to_vec()),
+        }
+    }
+
+    fn open(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(&nonce, data),
+            None => Ok(data.to_vec()),
+        }
+    }
+
+    fn overhead(&self) -> usize {
+        if self.cipher.is_some() {
+            AEAD_TAG_SIZE
+        } else {
+            0
+        }
+    }
+}
+
+// one direction of the data section with its options applied
+struct ChunkStream {
+    payload: ChunkCipher,
+    // ChunkMasking, also drives GlobalPadding
+    shake: Option<Shake128Reader>,
+    // AuthenticatedLength
+    length: Option<ChunkCipher>,
+    padding: bool,
+    // size and padding of a chunk whose header was read but body isn't complete yet
+    pending: Option<(usize, usize)>,
+}
+
+impl ChunkStream {
+    // https://github.com/v2fly/v2ray-core/blob/master/proxy/vmess/encoding/server.go
+    fn new(security: u8, option: u8, key: &[u8], iv: &[u8]) -> Result<Self> {
+        let payload = ChunkCipher::new(security, key, iv)?;
+
+        let shake = (option & OPTION_CHUNK_MASKING != 0).then(|| {
+            let mut shake = Shake128::default();
+            sha3::digest::Update::update(&mut shake, iv);
+            shake.finalize_xof()
+        });
+
+        // the plain chunk stream used by "none" has no padding nor length authentication
+        let padding = option & OPTION_GLOBAL_PADDING != 0 && security != SECURITY_NONE;
+        if padding && shake.is_none() {
+            return Err(Error::RustError("global padding requires chunk masking".to_string()));
+        }
+
+        let length = if option & OPTION_AUTHENTICATED_LENGTH != 0 && security != SECURITY_NONE {
+            let length_key = &hash::kdf(key, &[KDFSALT_CONST_AUTH_LEN])[..16];
+            Some(ChunkCipher::new(security, length_key, iv)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            payload,
+            shake,
+            length,
+            padding,
+            pending: None,
+        })
+    }
+
+    fn next_mask(&mut self) -> u16 {
+        let mut mask = [0u8; 2];
+        if let Some(shake) = self.shake.as_mut() {
+            shake.read(&mut mask);
+        }
+        u16::from_be_bytes(mask)
+    }
+
+    fn next_padding(&mut self) -> usize {
+        if self.padding {
+            (self.next_mask() % 64) as usize
+        } else {
+            0
+        }
+    }
+
+    fn size_bytes(&self) -> usize {
+        match &self.length {
+            Some(length) => 2 + length.overhead(),
+            None => 2,
+        }
+    }
+
+    fn decode_size(&mut self, src: &[u8]) -> Result<usize> {
+        if let Some(length) = self.length.as_mut() {
+            let size = length.open(src)?;
+            return Ok(u16::from_be_bytes([size[0], size[1]]) as usize + length.overhead());
+        }
+        let size = u16::from_be_bytes([src[0], src[1]]);
+        Ok((self.next_mask() ^ size) as usize)
+    }
+
+    fn encode_size(&mut self, size: usize) -> Result<Vec<u8>> {
+        if let Some(length) = self.length.as_mut() {
+            let size = (size - length.overhead()) as u16;
+            return length.seal(&size.to_be_bytes());
+        }
+        let size = self.next_mask() ^ size as u16;
+        Ok(size.to_be_bytes().to_vec())
+    }
+}
+
+// https://xtls.github.io/en/development/protocols/vmess.html#data-section
+//
+// +-------------------+-------------------+-------------------+
+// |      Length       |      Payload      |      Padding      |
+// +-------------------+-------------------+-------------------+
+// |   2 or 18 Bytes   |      L-P Bytes    |      P Bytes      |
+// +-------------------+-------------------+-------------------+
+pub struct VmessCodec {
+    reader: ChunkStream,
+    writer: ChunkStream,
+}
+
+impl Codec for VmessCodec {
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+        let reader = &mut self.reader;
+        let (size, padding) = match reader.pending {
+            Some(pending) => pending,
+            None => {
+                let size_bytes = reader.size_bytes();
+                if src.len() < size_bytes {
+                    return Ok(None);
+                }
+                let padding = reader.next_padding();
+                let size = reader.decode_size(&src[..size_bytes])?;
+                src.advance(size_bytes);
+                if size < padding + reader.payload.overhead() {
+                    return Err(Error::RustError("invalid chunk length".to_string()));
+                }
+                reader.pending = Some((size, padding));
+                (size, padding)
+            }
+        };
+        if src.len() < size {
+            return Ok(None);
+        }
+
+        reader.pending = None;
+        let chunk = src.split_to(size);
+        reader.payload.open(&chunk[..size - padding]).map(Some)
+    }
+
+    fn encode(&mut self, data: &[u8], dst: &mut BytesMut) -> Result<()> {
+        let writer = &mut self.writer;
+        for chunk in data.chunks(MAX_CHUNK_SIZE) {
+            let padding = writer.next_padding();
+            let size = chunk.len() + writer.payload.overhead() + padding;
+            let size = writer.encode_size(size)?;
+            let sealed = writer.payload.seal(chunk)?;
+
+            let mut random = vec![0u8; padding];
+            getrandom::getrandom(&mut random).map_err(|e| Error::RustError(e.to_string()))?;
+
+            dst.put_slice(&size);
+            dst.put_slice(&sealed);
+            dst.put_slice(&random);
+        }
+        Ok(())
+    }
+}
 
 impl <'a> ProxyStream<'a> {
-    async fn aead_decrypt(&mut self) -> Result<Vec<u8>> {
-        let key = crate::md5!(
-            &self.config.uuid.as_bytes(),
-            b"c48619fe-8f02-49e0-b9e9-edf763e17e21"
-        );
+    // the auth id only decrypts with the cmd key of the sending user
+    fn find_vmess_user(&self, auth_id: &[u8; 16]) -> Option<(User, Vec<u8>, u64)> {
+        self.config.active_users().into_iter().find_map(|user| {
+            let key = crate::md5!(&user.id.as_bytes(), b"c48619fe-8f02-49e0-b9e9-edf763e17e21");
+            decode_auth_id(&key, auth_id)
+                .ok()
+                .map(|timestamp| (user, key.to_vec(), timestamp))
+        })
+    }
 
+    // matches once the auth id and the header length both decrypt
+    pub fn detect_vmess(&self) -> Detection {
+        let peeked = self.peek_buffer(VMESS_MIN_HEADER_SIZE);
+        if peeked.len() < VMESS_MIN_HEADER_SIZE {
+            return Detection::NeedMore;
+        }
+
+        let auth_id: [u8; 16] = peeked[..16].try_into().unwrap();
+        match self.find_vmess_user(&auth_id) {
+            Some((_, key, _)) if decrypt_header_length(&key, &auth_id, &peeked[16..34], &peeked[34..42]).is_ok() => {
+                Detection::Match
+            }
+            _ => Detection::NoMatch,
+        }
+    }
+
+    async fn aead_decrypt(&mut self) -> Result<Vec<u8>> {
         // +-------------------+-------------------+-------------------+
         // |     Auth ID       |   Header Length   |       Nonce       |
         // +-------------------+-------------------+-------------------+
@@ -38,36 +359,21 @@ impl <'a> ProxyStream<'a> {
         let mut nonce = [0u8; 8];
         self.read_exact(&mut nonce).await?;
 
-        // https://github.com/v2fly/v2ray-core/blob/master/proxy/vmess/aead/kdf.go
-        let header_length = {
-            let header_length_key = &hash::kdf(
-                &key,
-                &[
-                    KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY,
-                    &auth_id,
-                    &nonce,
-                ],
-            )[..16];
-            let header_length_nonce = &hash::kdf(
-                &key,
-                &[
-                    KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV,
-                    &auth_id,
-                    &nonce,
-                ],
-            )[..12];
-
-            let payload = Payload {
-                msg: &len,
-                aad: &auth_id,
-            };
-
-            let len = Aes128Gcm::new(header_length_key.into())
-                .decrypt(header_length_nonce.into(), payload)
-                .map_err(|e| Error::RustError(e.to_string()))?;
-
-            ((len[0] as u16) << 8) | (len[1] as u16)
+        let (user, key, timestamp) = match self.find_vmess_user(&auth_id) {
+            Some(matched) => matched,
+            None => return Err(self.reject("vmess: invalid auth id")),
         };
+        let now = unix_time();
+        if now.abs_diff(timestamp) > AUTH_ID_WINDOW {
+            return Err(self.reject("vmess: auth id timestamp out of window"));
+        }
+        if !AUTH_ID_CACHE.insert(&auth_id, timestamp + AUTH_ID_WINDOW, now) {
+            return Err(self.reject("vmess: replayed auth id"));
+        }
+        self.authenticate(user);
+
+        // https://github.com/v2fly/v2ray-core/blob/master/proxy/vmess/aead/kdf.go
+        let header_length = decrypt_header_length(&key, &auth_id, &len, &nonce)?;
 
         // 16 bytes padding
         let mut cmd = vec![0u8; (header_length + 16) as _];
@@ -121,35 +427,58 @@ impl <'a> ProxyStream<'a> {
         let mut key = [0u8; 16];
         buf.read_exact(&mut key).await?;
 
-        // ignore options for now
+        // response authentication value, options, padding length + security, reserved
         let mut options = [0u8; 4];
         buf.read_exact(&mut options).await?;
+        let option = options[1];
+        let padding_length = options[2] >> 4;
+        let security = options[2] & 0x0f;
+        let chunk_stream = option & OPTION_CHUNK_STREAM != 0 && security != SECURITY_ZERO;
 
         let cmd = buf.read_u8().await?;
-        let is_tcp = cmd == 0x1;
 
-        let remote_port = {
-            let mut port = [0u8; 2];
-            buf.read_exact(&mut port).await?;
-            ((port[0] as u16) << 8) | (port[1] as u16)
+        let (remote_addr, remote_port) = if cmd == COMMAND_MUX {
+            (String::new(), 0)
+        } else {
+            let remote_port = {
+                let mut port = [0u8; 2];
+                buf.read_exact(&mut port).await?;
+                ((port[0] as u16) << 8) | (port[1] as u16)
+            };
+            let remote_addr = crate::common::parse_addr(&mut buf, crate::common::VLESS_ADDR).await?;
+            (remote_addr, remote_port)
+        };
+
+        // skip random value, then verify fnv1a over everything before the checksum
+        let mut padding = vec![0u8; padding_length as _];
+        buf.read_exact(&mut padding).await?;
+        let checksum_offset = buf.position() as usize;
+        let checksum = buf.read_u32().await?;
+        if hash::fnv1a32(&buf.get_ref()[..checksum_offset]) != checksum {
+            return Err(self.reject("vmess: invalid header checksum"));
+        }
+
+        let request_cipher = if chunk_stream {
+            Some(ChunkStream::new(security, option, &key, &iv)?)
+        } else {
+            None
         };
-        let remote_addr = crate::common::parse_addr(&mut buf).await?;
 
         // encrypt payload
         let key = &crate::sha256!(&key)[..16];
         let iv = &crate::sha256!(&iv)[..16];
 
         // https://github.com/v2ray/v2ray-core/blob/master/proxy/vmess/encoding/client.go#L196
-        let length_key = &hash::kdf(&key, &[KDFSALT_CONST_AEAD_RESP_HEADER_LEN_KEY])[..16];
-        let length_iv = &hash::kdf(&iv, &[KDFSALT_CONST_AEAD_RESP_HEADER_LEN_IV])[..12];
+        let length_key = &hash::kdf(key, &[KDFSALT_CONST_AEAD_RESP_HEADER_LEN_KEY])[..16];
+        let length_iv = &hash::kdf(iv, &[KDFSALT_CONST_AEAD_RESP_HEADER_LEN_IV])[..12];
         let length = Aes128Gcm::new(length_key.into())
             // 4 bytes header: https://github.com/v2ray/v2ray-core/blob/master/proxy/vmess/encoding/client.go#L238
             .encrypt(length_iv.into(), &4u16.to_be_bytes()[..])
             .map_err(|e| Error::RustError(e.to_string()))?;
-        self.write(&length).await?;
+        self.write_all(&length).await?;
 
-        let payload_key = &hash::kdf(&key, &[KDFSALT_CONST_AEAD_RESP_HEADER_KEY])[..16];
-        let payload_iv = &hash::kdf(&iv, &[KDFSALT_CONST_AEAD_RESP_HEADER_IV])[..12];
+        let payload_key = &hash::kdf(key, &[KDFSALT_CONST_AEAD_RESP_HEADER_KEY])[..16];
+        let payload_iv = &hash::kdf(iv, &[KDFSALT_CONST_AEAD_RESP_HEADER_IV])[..12];
         let header = {
             let header = [
                 options[0], // https://github.com/v2ray/v2ray-core/blob/master/proxy/vmess/encoding/client.go#L242
@@ -159,25 +488,147 @@ impl <'a> ProxyStream<'a> {
                 .encrypt(payload_iv.into(), &header[..])
                 .map_err(|e| Error::RustError(e.to_string()))?
         };
-        self.write(&header).await?;
+        self.write_all(&header).await?;
+        self.flush().await?;
 
-        if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
+        if let Some(reader) = request_cipher {
+            let writer = ChunkStream::new(security, option, key, iv)?;
+            self.set_codec(Box::new(VmessCodec { reader, writer }));
+        }
 
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
-                }
+        let result = match cmd {
+            COMMAND_TCP => self.handle_tcp_outbound(remote_addr, remote_port).await,
+            // without a chunk stream there is nothing to split datagrams on
+            COMMAND_UDP if self.codec.is_none() => self.handle_udp_outbound().await,
+            COMMAND_UDP => self.handle_vmess_udp(remote_addr, remote_port).await,
+            COMMAND_MUX => self.process_mux().await,
+            _ => return Err(Error::RustError(format!("vmess: unsupported command {cmd}"))),
+        };
+        if let Err(e) = result {
+            console_error!("error handling vmess command {}: {}", cmd, e)
+        }
+
+        Ok(())
+    }
+
+    // each chunk carries one datagram, and each reply goes back as its own chunk
+    async fn handle_vmess_udp(&mut self, remote_addr: String, remote_port: u16) -> Result<()> {
+        while let Some(packet) = self.read_chunk().await? {
+            if let Some(resp) = forward_dns_to(&self.config.dns_upstream, &remote_addr, remote_port, &packet).await {
+                self.write_all(&resp).await?;
+                self.flush().await?;
             }
-        } else {
-            if let Err(e) = self.handle_udp_outbound().await {
-                console_error!("error handling udp: {}", e)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::BlockEncrypt;
+
+    #[test]
+    fn test_decode_auth_id() {
+        let uuid = uuid::uuid!("96850032-1b92-46e9-a4f2-b99631456894").as_bytes();
+        let key = crate::md5!(&uuid, b"c48619fe-8f02-49e0-b9e9-edf763e17e21");
+
+        let mut plain = [0u8; 16];
+        plain[..8].copy_from_slice(&1_700_000_000u64.to_be_bytes());
+        plain[8..12].copy_from_slice(&[1, 2, 3, 4]);
+        let checksum = crc32fast::hash(&plain[..12]);
+        plain[12..].copy_from_slice(&checksum.to_be_bytes());
+
+        let auth_id_key = &hash::kdf(&key, &[KDFSALT_CONST_AUTH_ID_ENCRYPTION_KEY])[..16];
+        let mut block = plain.into();
+        Aes128::new(auth_id_key.into()).encrypt_block(&mut block);
+        let auth_id: [u8; 16] = block.into();
+
+        assert_eq!(decode_auth_id(&key, &auth_id).unwrap(), 1_700_000_000);
+
+        let mut forged = auth_id;
+        forged[0] ^= 1;
+        assert!(decode_auth_id(&key, &forged).is_err());
+    }
+
+    #[test]
+    fn test_vmess_codec() {
+        let (key, iv) = ([7u8; 16], [9u8; 16]);
+        let all_options = OPTION_CHUNK_STREAM
+            | OPTION_CHUNK_MASKING
+            | OPTION_GLOBAL_PADDING
+            | OPTION_AUTHENTICATED_LENGTH;
+        for security in [SECURITY_AES_128_GCM, SECURITY_CHACHA20_POLY1305, SECURITY_NONE] {
+            for option in [OPTION_CHUNK_STREAM, OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING, all_options] {
+                let mut client = VmessCodec {
+                    reader: ChunkStream::new(security, option, &key, &iv).unwrap(),
+                    writer: ChunkStream::new(security, option, &key, &iv).unwrap(),
+                };
+
+                let mut wire = BytesMut::new();
+                client.encode(b"hello", &mut wire).unwrap();
+                client.encode(b"world", &mut wire).unwrap();
+                let mut partial = wire.split_to(5);
+                assert!(client.decode(&mut partial).unwrap().is_none());
+                partial.unsplit(wire);
+
+                assert_eq!(client.decode(&mut partial).unwrap().unwrap(), b"hello");
+                assert_eq!(client.decode(&mut partial).unwrap().unwrap(), b"world");
+                assert!(partial.is_empty());
             }
         }
+    }
 
-        Ok(())
+    #[test]
+    fn test_vmess_known_chunks() {
+        use crate::common::from_hex;
+
+        // "hello" and "world!" framed the way v2fly's AuthenticationWriter does with key 00..0f
+        // and iv 10..1f; padding bytes are random on the wire, zeros here
+        let (key, iv) = ((0u8..16).collect::<Vec<_>>(), (16u8..32).collect::<Vec<_>>());
+        let masked = OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING;
+        let padded = masked | OPTION_GLOBAL_PADDING;
+        let cases = [
+            (SECURITY_AES_128_GCM, masked, "\
+                fc84a316c4d586054e854b4a74fc9040de5bd5eb74d1778e5540387f8943863177611c700a6d10a0\
+                8b2d5bffd7b1b9"),
+            (SECURITY_CHACHA20_POLY1305, OPTION_CHUNK_STREAM | OPTION_AUTHENTICATED_LENGTH, "\
+                43c08e81ea456a74bfb5f59eef350aaa77ccd7f66656047edbebabfc02352cca6f96fcc82771bc12\
+                1d05b8848d301412d88dda6fada048974edbb86cef3e96bde66f8bd09b13822da3d6ba7fc1a245"),
+            (SECURITY_AES_128_GCM, padded, "\
+                8e65a316c4d586054e854b4a74fc9040de5bd5eb74d1770000000000000000000000000000000000\
+                b6b440387f8943863177611c700a6d10a08b2d5bffd7b1b900000000"),
+            (SECURITY_CHACHA20_POLY1305, padded, "\
+                8e65d7f66656047edbebabfc02352cca6f96fcc82771bc0000000000000000000000000000000000\
+                b6b4dbb86cef3e96bde66f8bd09b13822da3d6ba7fc1a24500000000"),
+            (SECURITY_AES_128_GCM, padded | OPTION_AUTHENTICATED_LENGTH, "\
+                188f65e0d14d9b03cb778e9a1d8974e8b8eba316c4d586054e854b4a74fc9040de5bd5eb74d17700\
+                000000000000000000000000000000001a8fc160c94a14e20ca4d8955b24964e029a40387f894386\
+                3177611c700a6d10a08b2d5bffd7b1b9000000"),
+            (SECURITY_CHACHA20_POLY1305, padded | OPTION_AUTHENTICATED_LENGTH, "\
+                43d30adb2ec192420e2a0dfe8623f2b3c986d7f66656047edbebabfc02352cca6f96fcc82771bc00\
+                00000000000000000000000000000000121287dce3b67d01d77727d53c3abcee64dddbb86cef3e96\
+                bde66f8bd09b13822da3d6ba7fc1a245000000"),
+        ];
+        for (security, option, expected) in cases {
+            let expected = from_hex(expected);
+            let mut codec = VmessCodec {
+                reader: ChunkStream::new(security, option, &key, &iv).unwrap(),
+                writer: ChunkStream::new(security, option, &key, &iv).unwrap(),
+            };
+
+            let mut wire = BytesMut::from(&expected[..]);
+            assert_eq!(codec.decode(&mut wire).unwrap().unwrap(), b"hello");
+            assert_eq!(codec.decode(&mut wire).unwrap().unwrap(), b"world!");
+            assert!(wire.is_empty());
+
+            // without padding the whole chunk is deterministic
+            if option & OPTION_GLOBAL_PADDING == 0 {
+                let mut wire = BytesMut::new();
+                codec.encode(b"hello", &mut wire).unwrap();
+                codec.encode(b"world!", &mut wire).unwrap();
+                assert_eq!(&wire[..], &expected[..]);
+            }
+        }
     }
 }